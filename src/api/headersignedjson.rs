@@ -15,6 +15,7 @@ pub enum Signatory {
     Client,
     Contract,
     Directory,
+    Payment,
 }
 
 #[derive(Debug, EnumString)]
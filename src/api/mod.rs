@@ -7,7 +7,7 @@ use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 use std::{collections::HashMap, time::Duration};
 use url::Url;
-use ws_common::api::{Pof, WithdrawalState};
+use ws_common::api::{Pof, WithdrawalRequest, WithdrawalState};
 use ws_macros::{Sign, Timestamped};
 
 pub mod chronosort;
@@ -42,8 +42,83 @@ pub struct PubDefined {
     pub servicekey: ServicekeyCfg,
     pub settlement: SettlementCfg,
     pub payout: PayoutCfg,
+    // additional payout methods
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub payouts: Vec<PayoutCfg>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub metadata: Option<Metadata>,
+    // configurable but not published under /info
+    #[serde(default, skip_serializing)]
+    pub private: PrivateCfg,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct PrivateCfg {
+    // How long to wait for in-flight requests to finish on shutdown.
+    #[serde(with = "humantime_serde")]
+    pub drain_timeout: Duration,
+    // Tracker event log rotation.
+    pub log: LogCfg,
+    // How long to remember withdrawals by idempotency key, and resolved withdrawals by id.
+    #[serde(with = "humantime_serde")]
+    pub idempotency_retention: Duration,
+    // Relay directory settings.
+    pub directory: DirectoryCfg,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DirectoryCfg {
+    // Evict relays not heard from for this long.
+    #[serde(with = "humantime_serde")]
+    pub relay_ttl: Duration,
+    // Enrollment limits per relay role.
+    pub roles: RolesCfg,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct RolesCfg {
+    pub fronting: RoleCfg,
+    pub entropic: RoleCfg,
+    pub backing: RoleCfg,
+}
+
+impl RolesCfg {
+    pub fn role(&self, r: Role) -> &RoleCfg {
+        use Role::*;
+        match r {
+            Fronting => &self.fronting,
+            Entropic => &self.entropic,
+            Backing => &self.backing,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct RoleCfg {
+    // Maximum number of relays enrolled in the role.
+    pub max: Option<u32>,
+    // If set, only these relays may enroll in the role.
+    pub allow: Option<Vec<Base64<VerifyingKey>>>,
+}
+
+impl RoleCfg {
+    pub fn allows(&self, pk: &Base64<VerifyingKey>) -> bool {
+        self.allow.as_ref().map_or(true, |a| a.contains(pk))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct LogCfg {
+    // Rotate the current log file once it reaches this many bytes.
+    pub max_size: u64,
+    // Rotate the current log file once it is this old.
+    #[serde(with = "humantime_serde")]
+    pub max_age: Duration,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -54,6 +129,8 @@ pub struct PubDerived {
     pub version: Version,
     pub enrollment: Enrollment,
     pub directory: Directory,
+    // types of all enabled payout methods, the primary one first
+    pub payout_methods: Vec<String>,
 }
 
 // DIR SECTION
@@ -74,6 +151,14 @@ pub struct Relay {
     pub role: Role,
     pub address: String,
     pub versions: Versions,
+    // time of the last enrollment or heartbeat, set by the directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Heartbeat {
+    pub timestamp: i64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -90,17 +175,13 @@ pub struct Versions {
 pub struct RoleInfo {
     pub count: u32,
     pub restricted: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<u32>,
 }
 
 impl RoleInfo {
-    pub fn record(&mut self, delta: i8) -> bool {
-        match self.count.checked_add_signed(delta.into()) {
-            Some(i) => {
-                self.count = i;
-                true
-            }
-            None => false,
-        }
+    pub fn full(&self) -> bool {
+        self.max.map_or(false, |m| self.count >= m)
     }
 }
 
@@ -112,6 +193,33 @@ pub struct Enrollment {
 }
 
 impl Enrollment {
+    // empty enrollment with the configured limits
+    pub fn new(roles: &RolesCfg) -> Self {
+        let mut e = Self::default();
+        for r in [Role::Fronting, Role::Entropic, Role::Backing] {
+            let (ri, rc) = (e.role(r), roles.role(r));
+            ri.restricted = rc.allow.is_some();
+            ri.max = rc.max;
+        }
+        e
+    }
+
+    // set the counts to those of the given relay roles, returns false if they were different
+    pub fn recount(&mut self, roles: impl Iterator<Item = Role>) -> bool {
+        let mut counted = Enrollment::default();
+        for r in roles {
+            counted.role(r).count += 1;
+        }
+        let mut same = true;
+        for r in [Role::Fronting, Role::Entropic, Role::Backing] {
+            let n = counted.role(r).count;
+            let ri = self.role(r);
+            same &= ri.count == n;
+            ri.count = n;
+        }
+        same
+    }
+
     pub fn role(&mut self, r: Role) -> &mut RoleInfo {
         use Role::*;
         match r {
@@ -164,6 +272,9 @@ pub struct PayoutCfg {
     // Payment system type.
     #[serde(rename = "type")]
     pub ps_type: String,
+    // How the payment system is reached, independent of its type.
+    #[serde(default, skip_serializing)]
+    pub backend: Backend,
     // How often to check for withdrawal status changes.
     #[serde(with = "humantime_serde")]
     pub check_period: Duration,
@@ -174,6 +285,49 @@ pub struct PayoutCfg {
     // Optional info URL.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub info: Option<Url>,
+    // Key the payment system signs withdrawal state updates with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<Base64<VerifyingKey>>,
+    // HTTP client settings for talking to the payment system.
+    #[serde(default, skip_serializing)]
+    pub http: HttpCfg,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    // JSON over HTTP at the payout endpoint
+    #[default]
+    Http,
+    // in-process, completes every withdrawal without paying anything out; for testing only
+    Dummy,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct HttpCfg {
+    // How long to wait for a connection to be established.
+    #[serde(with = "humantime_serde")]
+    pub connect_timeout: Duration,
+    // How long to wait for a complete response.
+    #[serde(with = "humantime_serde")]
+    pub request_timeout: Duration,
+    // How many times to retry a failed idempotent request.
+    pub retries: u32,
+    // Delay before the first retry, doubled for every further one.
+    #[serde(with = "humantime_serde")]
+    pub backoff: Duration,
+    pub tls: TlsCfg,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct TlsCfg {
+    // PEM file with the CA certificates to trust instead of the system ones.
+    pub ca_file: Option<PathBuf>,
+    // PEM files with the client certificate chain and key to authenticate with.
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -211,10 +365,25 @@ pub struct Servicekey {
 pub struct SKContract {
     pub public_key: Base64<VerifyingKey>,
     pub signature: Base64<SignatureBytes>,
+    // the servicekey this contract was issued for
+    pub servicekey: Base64<VerifyingKey>,
     pub settlement_open: i64,
     pub settlement_close: i64,
 }
 
+impl SKContract {
+    // the message signed by the contract on activation
+    pub fn message(&self) -> String {
+        vec![
+            self.public_key.to_string(),
+            self.servicekey.to_string(),
+            self.settlement_open.to_string(),
+            self.settlement_close.to_string(),
+        ]
+        .join(":")
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, Sign, Timestamped)]
 pub struct Sharetoken {
     pub version: u8,
@@ -246,6 +415,100 @@ impl Sharetoken {
     }
 }
 
+// contract-signed record of a single reward distribution
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Receipt {
+    pub public_key: Base64<VerifyingKey>,
+    pub signature: Base64<SignatureBytes>,
+    pub servicekey: String,
+    pub relay: String,
+    // sharetokens of the relay for the servicekey
+    pub tokens: Decimal,
+    // sharetokens of all relays for the servicekey
+    pub total: Decimal,
+    pub reward: Decimal,
+    pub settled_at: i64,
+}
+
+impl Receipt {
+    // the message signed by the contract on settlement
+    pub fn message(&self) -> String {
+        vec![
+            self.public_key.to_string(),
+            self.servicekey.clone(),
+            self.relay.clone(),
+            self.tokens.to_string(),
+            self.total.to_string(),
+            self.reward.to_string(),
+            self.settled_at.to_string(),
+        ]
+        .join(":")
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ReceiptsQuery {
+    // only receipts for settlements at or after this time
+    #[serde(default)]
+    pub since: i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StatementQuery {
+    // utime to list entries from, defaults to 30 days ago
+    pub since: Option<i64>,
+    // index of the first entry to return
+    #[serde(default)]
+    pub offset: usize,
+    // maximum number of entries to return
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryState {
+    Pending,
+    Complete,
+    Aborted,
+}
+
+// a single credit (positive amount) or debit (negative amount) of a relay balance
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StatementEntry {
+    pub time: i64,
+    // set for distributions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub servicekey: Option<String>,
+    // set for withdrawals
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<EntryState>,
+    pub amount: Decimal,
+    // available balance after this entry
+    pub balance: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Statement {
+    pub currency: String,
+    // utime the entries are listed from
+    pub since: i64,
+    // available balance at `since`
+    pub opening: Decimal,
+    // total number of entries
+    pub total: usize,
+    pub offset: usize,
+    pub entries: Vec<StatementEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct KeyedWithdrawalRequest {
+    #[serde(flatten)]
+    pub request: WithdrawalRequest,
+    // client-supplied key to safely retry the request with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct WithdrawalStateUpdate {
     pub id: String,
@@ -1,5 +1,5 @@
 use crate::{api::digestible::Digestible, api::signable::Signable};
-use ed25519_dalek::Verifier;
+use ed25519_dalek::{SignatureError, Verifier};
 use serde::{Deserialize, Deserializer};
 use std::ops::Deref;
 
@@ -17,6 +17,18 @@ where
 
 pub struct Signed<T: Signable>(pub T);
 
+impl<T> Signed<T>
+where
+    T: Signable + Digestible,
+{
+    // wrap t if its signature is valid
+    pub fn verify(t: T) -> Result<Signed<T>, SignatureError> {
+        t.public_key() // Signer
+            .verify(t.digest().as_bytes(), &t.signature())
+            .map(|()| Signed(t))
+    }
+}
+
 impl<'de, T> Deserialize<'de> for Signed<T>
 where
     T: Signable + Deserialize<'de> + Digestible,
@@ -26,9 +38,6 @@ where
         D: Deserializer<'de>,
     {
         let t = T::deserialize(de)?;
-        t.public_key() // Signer
-            .verify(t.digest().as_bytes(), &t.signature())
-            .map_err(serde::de::Error::custom)
-            .map(|()| Signed(t))
+        Signed::verify(t).map_err(serde::de::Error::custom)
     }
 }
@@ -1,7 +1,7 @@
 use crate::{
     api::{
-        Directory, Metadata, PayoutCfg, PubDefined, PubDerived, Public, ServicekeyCfg,
-        SettlementCfg,
+        Backend, Directory, DirectoryCfg, Enrollment, HttpCfg, LogCfg, Metadata, PayoutCfg,
+        PrivateCfg, PubDefined, PubDerived, Public, RolesCfg, ServicekeyCfg, SettlementCfg, TlsCfg,
     },
     VERSION,
 };
@@ -33,16 +33,62 @@ impl Default for PubDefined {
             payout: PayoutCfg {
                 endpoint: endp.clone(),
                 ps_type: "dummy".to_string(),
+                backend: Backend::Http,
                 check_period: Duration::from_secs(5),
                 min_withdrawal: Some(0),
                 max_withdrawal: Some(u64::MAX),
                 info: Some(endp.clone()),
+                public_key: None,
+                http: HttpCfg::default(),
             },
+            payouts: Vec::new(),
             metadata: Some(Metadata {
                 name: Some("PLEASE CONFIGURE ME".to_string()),
                 operator: Some("TEST CONTRACT WITH DEFAULT CONFIG".to_string()),
                 ..Default::default()
             }),
+            private: PrivateCfg::default(),
+        }
+    }
+}
+
+impl Default for PrivateCfg {
+    fn default() -> Self {
+        Self {
+            drain_timeout: Duration::from_secs(30),
+            log: LogCfg::default(),
+            idempotency_retention: Duration::from_secs(7 * 24 * 3600),
+            directory: DirectoryCfg::default(),
+        }
+    }
+}
+
+impl Default for DirectoryCfg {
+    fn default() -> Self {
+        Self {
+            relay_ttl: Duration::from_secs(300),
+            roles: RolesCfg::default(),
+        }
+    }
+}
+
+impl Default for HttpCfg {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(30),
+            retries: 3,
+            backoff: Duration::from_millis(500),
+            tls: TlsCfg::default(),
+        }
+    }
+}
+
+impl Default for LogCfg {
+    fn default() -> Self {
+        Self {
+            max_size: 64 * 1024 * 1024,
+            max_age: Duration::from_secs(24 * 3600),
         }
     }
 }
@@ -55,11 +101,15 @@ pub fn mkpublic(def: PubDefined, pk: VerifyingKey) -> Public {
             pubkey: Base64(pk),
             public_key: Base64(pk),
             version: VERSION.clone(),
-            enrollment: Default::default(),
+            enrollment: Enrollment::new(&def.private.directory.roles),
             directory: Directory {
                 endpoint: def.endpoint.clone(),
                 public_key: Base64(pk),
             },
+            payout_methods: std::iter::once(&def.payout)
+                .chain(&def.payouts)
+                .map(|p| p.ps_type.clone())
+                .collect(),
         },
     }
 }
@@ -0,0 +1,283 @@
+use super::tracker::Action;
+use crate::api::LogCfg;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::{debug, warn};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+use ws_common::time::utimenow;
+
+const PREFIX: &'static str = &"events_";
+const SUFFIX: &'static str = &".jsonl";
+const GZ_SUFFIX: &'static str = &".jsonl.gz";
+
+/// The enum of all possible tracker log events.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    // submission of a single sharetoken: servicekey public key, relay public key
+    Submission(String, String),
+    // change in the relay's balance on settlement: servicekey public key, relay public key, delta
+    Distribution(String, String, Decimal),
+    // pending withdrawal: relay public key, delta
+    WithdrawalPending(String, Decimal),
+    // final withdrawal: relay public key, action
+    WithdrawalFinal(String, Action),
+    // settlement of a single servicekey: servicekey public key
+    Settlement(String),
+}
+
+/// The end of the event log as of a save: the start utime of the current file and the bytes
+/// written to it.
+#[derive(Clone, Copy, Debug)]
+pub struct LogEnd {
+    start: i64,
+    size: u64,
+}
+
+/// Append-only log of `(utime, Event)` as JSON lines. The current file is rotated by size and
+/// age into gzip-compressed archives named after the utime the file was started at.
+pub struct EventLog {
+    /// Path to the log folder on disk. (owned)
+    dir: PathBuf,
+    /// Rotation settings.
+    cfg: LogCfg,
+    /// Start utime of the current file.
+    start: i64,
+    /// Bytes written to the current file.
+    size: u64,
+    /// Events added since the last save.
+    buf: Vec<(i64, Event)>,
+}
+
+fn plain_path(dir: &Path, start: i64) -> PathBuf {
+    dir.join(format!("{}{}{}", PREFIX, start, SUFFIX))
+}
+
+fn gz_path(dir: &Path, start: i64) -> PathBuf {
+    dir.join(format!("{}{}{}", PREFIX, start, GZ_SUFFIX))
+}
+
+/// List the log files in `dir` by start utime, preferring the archive if a file is in both forms.
+fn log_files(dir: &Path) -> Result<BTreeMap<i64, PathBuf>, io::Error> {
+    let mut files = BTreeMap::new();
+    for e in std::fs::read_dir(dir)? {
+        let path = e?.path();
+        let name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(n) => n,
+            None => continue,
+        };
+        let (start, gz) = match name.strip_prefix(PREFIX) {
+            Some(n) => match (n.strip_suffix(GZ_SUFFIX), n.strip_suffix(SUFFIX)) {
+                (Some(n), _) => (n.parse::<i64>(), true),
+                (None, Some(n)) => (n.parse::<i64>(), false),
+                _ => continue,
+            },
+            None => continue,
+        };
+        if let Ok(start) = start {
+            if gz || !files.contains_key(&start) {
+                files.insert(start, path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Compress a rotated plain log file. The archive is complete before the plain file is removed.
+fn compress(dir: &Path, start: i64) -> Result<(), io::Error> {
+    let src = plain_path(dir, start);
+    let dst = gz_path(dir, start);
+    let tmp = dst.with_extension("gz.tmp");
+    let mut enc = GzEncoder::new(File::create(&tmp)?, Compression::default());
+    io::copy(&mut File::open(&src)?, &mut enc)?;
+    enc.finish()?.sync_all()?;
+    std::fs::rename(&tmp, &dst)?;
+    std::fs::remove_file(&src)
+}
+
+fn compress_bg(dir: PathBuf, start: i64) {
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = compress(&dir, start) {
+            warn!("Could not compress event log started at {}: {}", start, e);
+        }
+    });
+}
+
+impl EventLog {
+    /// Opens the event log in `dir`, rotating any files left over from a previous run.
+    pub async fn open(dir: PathBuf, cfg: LogCfg) -> Result<Self, io::Error> {
+        tokio::fs::create_dir_all(&dir).await?;
+        let d = dir.clone();
+        let leftover = tokio::task::spawn_blocking(move || log_files(&d)).await??;
+        let mut start = utimenow();
+        for (s, path) in leftover {
+            if path == plain_path(&dir, s) {
+                compress_bg(dir.clone(), s)
+            }
+            // never reuse a start time, the reader relies on those being unique
+            start = start.max(s + 1);
+        }
+        Ok(EventLog {
+            dir,
+            cfg,
+            start,
+            size: 0,
+            buf: Vec::new(),
+        })
+    }
+
+    pub fn add(&mut self, e: Event) {
+        self.buf.push((utimenow(), e))
+    }
+
+    /// Path to the log folder, for use with `EventReader`.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Saves the log like `save`, returning its end. Reading up to it yields exactly the events
+    /// added so far, even while more are added.
+    pub async fn sync(&mut self) -> Result<LogEnd, io::Error> {
+        self.save().await?;
+        Ok(LogEnd {
+            start: self.start,
+            size: self.size,
+        })
+    }
+
+    /// Appends the events added since the last save and rotates the file if due.
+    pub async fn save(&mut self) -> Result<(), io::Error> {
+        if self.buf.len() > 0 {
+            let mut lines = Vec::new();
+            for ev in &self.buf {
+                serde_json::to_writer(&mut lines, ev)?;
+                lines.push(b'\n');
+            }
+            let mut f = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(plain_path(&self.dir, self.start))
+                .await?;
+            f.write_all(&lines).await?;
+            f.flush().await?;
+            self.size += lines.len() as u64;
+            self.buf.clear();
+        }
+
+        let now = utimenow();
+        let age = now.saturating_sub(self.start);
+        if self.size > 0
+            && (self.size >= self.cfg.max_size || age >= self.cfg.max_age.as_secs() as i64)
+        {
+            debug!(
+                "Rotating event log started at {} ({} bytes).",
+                self.start, self.size
+            );
+            compress_bg(self.dir.clone(), self.start);
+            // never reuse a start time, the reader relies on those being unique
+            self.start = now.max(self.start + 1);
+            self.size = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the events logged in `[from, to)` across all current and rotated log files, oldest
+/// first. Reading is blocking, so use from `tokio::task::spawn_blocking` in async contexts.
+pub struct EventReader {
+    /// Log files left to read with their start utimes.
+    files: VecDeque<(i64, PathBuf)>,
+    cur: Option<Box<dyn BufRead + Send>>,
+    from: i64,
+    to: i64,
+    /// Where to stop reading, if not at the end of the last file.
+    end: Option<LogEnd>,
+}
+
+impl EventReader {
+    pub fn new(dir: &Path, from: i64, to: i64) -> Result<Self, io::Error> {
+        let files: Vec<(i64, PathBuf)> = log_files(dir)?.into_iter().collect();
+        // a file holds events from its start up to the start of the next one
+        let files = files
+            .iter()
+            .enumerate()
+            .filter(|(i, (start, _))| {
+                *start < to && files.get(i + 1).map_or(true, |(next, _)| *next > from)
+            })
+            .map(|(_, f)| f.clone())
+            .collect();
+        Ok(EventReader {
+            files,
+            cur: None,
+            from,
+            to,
+            end: None,
+        })
+    }
+
+    /// Stop reading at `end`, ignoring events added to the log later.
+    pub fn until(mut self, end: LogEnd) -> Self {
+        self.files
+            .retain(|(start, _)| *start < end.start || (*start == end.start && end.size > 0));
+        self.end = Some(end);
+        self
+    }
+
+    fn next_file(&mut self) -> Option<Result<(), io::Error>> {
+        let (start, mut p) = self.files.pop_front()?;
+        let limit = match self.end {
+            Some(end) if end.start == start => end.size,
+            _ => u64::MAX,
+        };
+        let mut f = File::open(&p);
+        if let Err(e) = &f {
+            // the file may have been rotated in the meantime
+            if e.kind() == io::ErrorKind::NotFound && !p.to_string_lossy().ends_with(GZ_SUFFIX) {
+                p = p.with_extension("jsonl.gz");
+                f = File::open(&p);
+            }
+        }
+        Some(f.map(|f| {
+            self.cur = Some(if p.to_string_lossy().ends_with(GZ_SUFFIX) {
+                Box::new(BufReader::new(GzDecoder::new(f).take(limit)))
+            } else {
+                Box::new(BufReader::new(f.take(limit)))
+            })
+        }))
+    }
+}
+
+impl Iterator for EventReader {
+    type Item = Result<(i64, Event), io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let r = match self.cur.as_mut() {
+                Some(r) => r,
+                None => match self.next_file()? {
+                    Ok(()) => continue,
+                    Err(e) => return Some(Err(e)),
+                },
+            };
+            let mut line = String::new();
+            match r.read_line(&mut line) {
+                Ok(0) => self.cur = None,
+                Ok(_) => match serde_json::from_str::<(i64, Event)>(&line) {
+                    Ok((t, _)) if t < self.from || t >= self.to => (),
+                    Ok(ev) => return Some(Ok(ev)),
+                    // a partially written last line of the current file
+                    Err(_) if !line.ends_with('\n') => self.cur = None,
+                    Err(e) => return Some(Err(e.into())),
+                },
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
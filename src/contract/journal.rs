@@ -0,0 +1,216 @@
+use super::tracker::Action;
+use crate::api::{Receipt, Sharetoken};
+use log::{debug, warn};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+};
+use ws_common::api::Withdrawal;
+
+const JOURNAL_FILE: &'static str = &"journal.jsonl";
+const SNAPSHOT_FILE: &'static str = &"snapshot.json";
+
+/// A single durable change to the tracker state. Records are written to the journal before the
+/// change takes effect, so replaying them on top of the last snapshot rebuilds the state exactly.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Record {
+    // sharetoken enqueued for settlement
+    Push(Sharetoken),
+    // settlement of all due sharetokens: sharetoken filenames, receipts of the resulting rewards
+    Settle(Vec<String>, Vec<Receipt>),
+    // pending balance change: relay public key, delta
+    Draft(String, Decimal),
+    // pending balance change finalized: relay public key, action
+    Commit(String, Action),
+    // pending balance change of a withdrawal finalized: withdrawal id, relay public key, action
+    Final(String, String, Action),
+    // pof used for servicekey activation: spent pofs registry key, pof expiration
+    Spend(String, i64),
+    // withdrawal requested with an idempotency key: keyed withdrawals registry key, request time,
+    // resulting withdrawal if known yet
+    Keyed(String, i64, Option<Withdrawal>),
+    // withdrawal requested with an idempotency key failed: keyed withdrawals registry key
+    Unkeyed(String),
+}
+
+/// Snapshot of the entire durable tracker state as of the journal record `seq`.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Snapshot {
+    pub seq: u64,
+    pub balances: HashMap<String, (Decimal, Decimal)>,
+    pub spent: HashMap<String, i64>,
+    pub keyed: HashMap<String, (i64, Option<Withdrawal>)>,
+    pub finished: HashMap<String, i64>,
+    pub queue: Vec<Sharetoken>,
+    pub archive_q: Vec<Sharetoken>,
+    pub receipts_q: Vec<Receipt>,
+}
+
+/// Append-only, fsynced journal of tracker `Record`s, compacted by writing a `Snapshot`.
+pub struct Journal {
+    /// Path to the tracker root folder on disk. (shared)
+    root_path: PathBuf,
+    /// The journal file, opened for appending.
+    file: File,
+    /// Length of the journal file up to the last durably appended record.
+    end: u64,
+    /// Whether a failed append could not be undone, no more records are appended then.
+    poisoned: bool,
+    /// Sequence number of the last written record.
+    seq: u64,
+    /// Number of records written since the last snapshot.
+    len: usize,
+}
+
+impl Journal {
+    /// Opens the journal under `root_path`, returning it along with the last snapshot (if any) and
+    /// the records written after it, in order.
+    pub async fn open(
+        root_path: &Path,
+    ) -> Result<(Journal, Option<Snapshot>, Vec<Record>), io::Error> {
+        let snapshot: Option<Snapshot> = match fs::read(root_path.join(SNAPSHOT_FILE)).await {
+            Ok(b) => Some(serde_json::from_slice(&b)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let mut seq = snapshot.as_ref().map_or(0, |s| s.seq);
+
+        let mut records = Vec::new();
+        // end of the last complete record, anything after it was never acknowledged
+        let mut end = 0;
+        match fs::read(root_path.join(JOURNAL_FILE)).await {
+            Ok(b) => {
+                let mut lines = b.split_inclusive(|c| *c == b'\n').peekable();
+                while let Some(line) = lines.next() {
+                    // only the last record can have been cut short by an interrupted write
+                    let last = lines.peek().is_none();
+                    match serde_json::from_slice::<(u64, Record)>(line) {
+                        // the newline is written along with the record, so it is incomplete
+                        Ok(_) if last && !line.ends_with(b"\n") => {
+                            warn!("Truncated tracker journal record dropped.")
+                        }
+                        Ok((n, r)) => {
+                            end += line.len() as u64;
+                            // already contained in the snapshot otherwise
+                            if n > seq {
+                                seq = n;
+                                records.push(r)
+                            }
+                        }
+                        Err(e) if last => warn!("Truncated tracker journal record dropped: {}", e),
+                        Err(e) => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("corrupt tracker journal record after {}: {}", seq, e),
+                            ))
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        debug!("{} tracker journal records to replay.", records.len());
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(root_path.join(JOURNAL_FILE))
+            .await?;
+        // appended records have to follow the last complete one
+        if file.metadata().await?.len() > end {
+            file.set_len(end).await?;
+            file.sync_all().await?;
+        }
+
+        let len = records.len();
+        Ok((
+            Journal {
+                root_path: root_path.to_path_buf(),
+                file,
+                end,
+                poisoned: false,
+                seq,
+                len,
+            },
+            snapshot,
+            records,
+        ))
+    }
+
+    /// Durably appends a record. Only returns once the record has been synced to disk.
+    pub async fn append(&mut self, r: &Record) -> Result<(), io::Error> {
+        self.append_all(std::slice::from_ref(r)).await
+    }
+
+    /// Durably appends records in order with a single write and sync. Only returns once all of
+    /// them have been synced to disk, if that fails none of them is kept.
+    pub async fn append_all(&mut self, rs: &[Record]) -> Result<(), io::Error> {
+        if self.poisoned {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "tracker journal unusable after a failed append",
+            ));
+        }
+        if rs.is_empty() {
+            return Ok(());
+        }
+        let mut buf = Vec::new();
+        for (i, r) in (self.seq + 1..).zip(rs) {
+            serde_json::to_writer(&mut buf, &(i, r))?;
+            buf.push(b'\n');
+        }
+        if let Err(e) = write_synced(&mut self.file, &buf).await {
+            // cut off what made it to the file, so the next records follow the last complete one
+            let undone = async {
+                self.file.set_len(self.end).await?;
+                self.file.sync_data().await
+            };
+            if let Err(ue) = undone.await {
+                warn!("Failed tracker journal append could not be undone: {}", ue);
+                self.poisoned = true;
+            }
+            return Err(e);
+        }
+        self.end += buf.len() as u64;
+        self.seq += rs.len() as u64;
+        self.len += rs.len();
+        Ok(())
+    }
+
+    /// Number of records written since the last snapshot.
+    pub fn records(&self) -> usize {
+        self.len
+    }
+
+    /// Atomically replaces the snapshot with `s` and truncates the journal. `s` must reflect the
+    /// state after all records appended so far.
+    pub async fn compact(&mut self, mut s: Snapshot) -> Result<(), io::Error> {
+        s.seq = self.seq;
+        let tmp = self.root_path.join(SNAPSHOT_FILE.to_string() + ".tmp");
+        let mut f = File::create(&tmp).await?;
+        f.write_all(&serde_json::to_vec(&s)?).await?;
+        f.sync_all().await?;
+        fs::rename(&tmp, self.root_path.join(SNAPSHOT_FILE)).await?;
+        // records up to s.seq are skipped on replay, so a crash before truncating is harmless
+        self.file.set_len(0).await?;
+        self.file.sync_all().await?;
+        self.end = 0;
+        self.len = 0;
+        debug!("Tracker snapshot written at journal record {}.", s.seq);
+        Ok(())
+    }
+}
+
+async fn write_synced(f: &mut File, b: &[u8]) -> Result<(), io::Error> {
+    f.write_all(b).await?;
+    f.sync_data().await
+}
@@ -1,58 +1,110 @@
 use crate::{
-    api::{headersignedjson::HeaderSignedJson, signed::Signed, ActivationRequest},
-    api::{SKContract, Sharetoken},
+    api::{
+        headersignedjson::{HeaderSignedJson, Signatory},
+        signed::Signed,
+        ActivationRequest,
+    },
+    api::{
+        KeyedWithdrawalRequest, Receipt, ReceiptsQuery, SKContract, Sharetoken, Statement,
+        StatementQuery, WithdrawalStateUpdate,
+    },
 };
 use axum::{
-    body::Body,
-    extract::{rejection::JsonRejection, State},
-    response::IntoResponse,
+    body::Bytes,
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        Query, State,
+    },
+    http::{header::CONTENT_TYPE, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
     Json,
 };
 use ed25519_dalek::Signer;
-use hyper::{body, Method, Request};
-use log::debug;
+use log::{debug, warn};
 use rust_decimal::Decimal;
 use std::time::SystemTime;
 use ws_common::{
-    api::{HttpClient, Status, Withdrawal, WithdrawalRequest, WithdrawalState},
+    api::{Status, Withdrawal},
     b64e::Base64,
     time::utime,
 };
 
-use self::tracker::BalanceView;
+use self::{
+    eventlog::EventReader,
+    payment::PsError,
+    tracker::{Action, BalanceViewV1},
+    validate::{PofError, SharetokenError},
+    watcher::{PendingWithdrawal, Resolution},
+};
 
 pub mod calc;
+pub mod eventlog;
+pub mod journal;
+pub mod payment;
+pub mod receipts;
+pub mod statement;
 pub mod tracker;
+pub mod validate;
+pub mod watcher;
+
+#[cfg(test)]
+mod tests;
 
 pub async fn activate_post_handler(
     State(st): crate::state::Safe,
     body: Result<Json<ActivationRequest>, JsonRejection>,
 ) -> impl IntoResponse {
     match body {
-        // TODO validate payload
-        Ok(Json(_)) => {
+        Ok(Json(payload)) => {
             let k = &st.crypto.key;
-            let st = st.read().await;
+            let (pofsources, skd, subw, pk, tracker) = {
+                let st = st.read().await;
+                (
+                    st.public.defined.pofsources.clone(),
+                    st.public.defined.servicekey.duration,
+                    st.public.defined.settlement.submission_window,
+                    st.public.derived.public_key.clone(),
+                    st.tracker.clone(),
+                )
+            };
 
             let now = SystemTime::now();
-            let skd = st.public.defined.servicekey.duration;
-            let subw = st.public.defined.settlement.submission_window;
 
-            let pk = st.public.derived.public_key;
+            if let Err(e) = validate::pof(&payload.pof, &pofsources, utime(now)) {
+                debug!("/servicekey/activate pof rejected: {}", e);
+                return Json(Status::from(e)).into_response();
+            }
+
+            let spent = tracker.write().await.spend(&payload.pof).await;
+            match spent {
+                Ok(true) => (),
+                Ok(false) => {
+                    debug!("/servicekey/activate pof rejected: already spent");
+                    return Json(Status::from(PofError::Spent)).into_response();
+                }
+                Err(e) => {
+                    return Json(Status {
+                        code: 500,
+                        desc: format!("could not record spent pof: {}", e),
+                    })
+                    .into_response()
+                }
+            }
+
             let so = now + skd;
             let ss = so + subw;
 
             let uso = utime(so);
             let uss = utime(ss);
 
-            let msg = vec![pk.to_string(), uso.to_string(), uss.to_string()].join(":");
-
-            let skc = SKContract {
+            let mut skc = SKContract {
                 public_key: pk,
-                signature: Base64(k.sign(msg.as_bytes()).to_bytes()),
+                signature: Base64([0; 64]),
+                servicekey: payload.public_key,
                 settlement_open: uso,
                 settlement_close: uss,
             };
+            skc.signature = Base64(k.sign(skc.message().as_bytes()).to_bytes());
             Json(skc).into_response()
         }
         Err(e) => Json(Status {
@@ -71,19 +123,36 @@ pub async fn submit_post_handler(
     match body {
         Ok(Json(payload)) => {
             debug!("/submit body is OK");
-            let st = st.write().await;
-            (if payload.contract.public_key != st.public.derived.public_key {
-                Json(Status {
-                    code: 400,
-                    desc: "Sharetoken is not for this contract".to_string(),
-                })
-            } else {
-                // TODO channel send
-                st.tracker.write().await.push(payload.0);
-                Json(Status {
-                    code: 200,
-                    desc: "OK".to_string(),
-                })
+            let (pk, duration, tracker) = {
+                let st = st.read().await;
+                (
+                    st.public.derived.public_key.clone(),
+                    st.public.defined.servicekey.duration,
+                    st.tracker.clone(),
+                )
+            };
+            (match validate::sharetoken(&payload, &pk, duration, utime(SystemTime::now())) {
+                Err(e) => {
+                    debug!("/submit sharetoken rejected: {}", e);
+                    Json(Status::from(e))
+                }
+                Ok(()) => {
+                    let pushed = tracker.write().await.push(payload.0).await;
+                    match pushed {
+                        Ok(true) => Json(Status {
+                            code: 200,
+                            desc: "OK".to_string(),
+                        }),
+                        Ok(false) => {
+                            debug!("/submit sharetoken rejected: duplicate");
+                            Json(Status::from(SharetokenError::Duplicate))
+                        }
+                        Err(e) => Json(Status {
+                            code: 500,
+                            desc: format!("could not record sharetoken: {}", e),
+                        }),
+                    }
+                }
             })
             .into_response()
         }
@@ -98,84 +167,266 @@ pub async fn submit_post_handler(
     }
 }
 
+/// Parse a batch of sharetokens, given either as a JSON array or as NDJSON. Sharetokens which
+/// cannot be parsed are reported individually so the rest of the batch can still be accepted.
+fn parse_batch(
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Vec<Result<Sharetoken, Status>>, Status> {
+    let ndjson = headers
+        .get(CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .map_or(false, |ct| ct.contains("ndjson"));
+    let items: Vec<Result<serde_json::Value, serde_json::Error>> = if ndjson {
+        // each line stands on its own, so a malformed one only fails itself
+        body.split(|b| *b == b'\n')
+            .filter(|l| !l.iter().all(u8::is_ascii_whitespace))
+            .map(serde_json::from_slice::<serde_json::Value>)
+            .collect()
+    } else {
+        // a malformed array cannot be split into sharetokens
+        serde_json::from_slice::<Vec<serde_json::Value>>(body)
+            .map_err(|e| Status {
+                code: 400,
+                desc: e.to_string(),
+            })?
+            .into_iter()
+            .map(Ok)
+            .collect()
+    };
+    Ok(items
+        .into_iter()
+        .map(|v| {
+            v.and_then(serde_json::from_value).map_err(|e| Status {
+                code: 400,
+                desc: e.to_string(),
+            })
+        })
+        .collect())
+}
+
+pub async fn submit_batch_post_handler(
+    State(st): crate::state::Safe,
+    headers: HeaderMap,
+    body: Bytes,
+) -> axum::response::Result<Json<Vec<Status>>, Json<Status>> {
+    debug!("Entered /submit/batch handler.");
+    let items = parse_batch(&headers, &body).map_err(|e| {
+        debug!("/submit/batch body is NOT OK: {:?}", e);
+        Json(e)
+    })?;
+    debug!("/submit/batch body is OK, {} sharetokens", items.len());
+
+    let (pk, duration, tracker) = {
+        let st = st.read().await;
+        (
+            st.public.derived.public_key.clone(),
+            st.public.defined.servicekey.duration,
+            st.tracker.clone(),
+        )
+    };
+    let now = utime(SystemTime::now());
+
+    // signature checks are cpu-bound, so split them evenly across blocking threads
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let size = (items.len() + threads - 1) / threads;
+    let mut items = items.into_iter();
+    let mut tasks = Vec::new();
+    loop {
+        let part: Vec<_> = items.by_ref().take(size.max(1)).collect();
+        if part.is_empty() {
+            break;
+        }
+        let pk = pk.clone();
+        tasks.push(tokio::task::spawn_blocking(move || {
+            part.into_iter()
+                .map(|r| {
+                    let st = Signed::verify(r?)
+                        .map_err(|_| Status::from(SharetokenError::InvalidSignature))?;
+                    validate::sharetoken(&st, &pk, duration, now).map_err(Status::from)?;
+                    Ok(st.0)
+                })
+                .collect::<Vec<Result<Sharetoken, Status>>>()
+        }));
+    }
+    let mut checked = Vec::new();
+    for t in tasks {
+        checked.extend(t.await.map_err(|e| {
+            Json(Status {
+                code: 500,
+                desc: format!("could not verify sharetokens: {}", e),
+            })
+        })?);
+    }
+
+    // journaled all at once, rejected sharetokens keep their place in the response
+    let mut res = Vec::with_capacity(checked.len());
+    let mut sts = Vec::new();
+    for r in checked {
+        match r {
+            Ok(st) => {
+                sts.push(st);
+                res.push(None);
+            }
+            Err(e) => res.push(Some(e)),
+        }
+    }
+    let n = sts.len();
+    let pushed: Vec<Status> = match tracker.write().await.push_batch(sts).await {
+        Ok(fresh) => fresh
+            .into_iter()
+            .map(|fresh| match fresh {
+                true => Status {
+                    code: 200,
+                    desc: "OK".to_string(),
+                },
+                false => Status::from(SharetokenError::Duplicate),
+            })
+            .collect(),
+        Err(e) => (0..n)
+            .map(|_| Status {
+                code: 500,
+                desc: format!("could not record sharetoken: {}", e),
+            })
+            .collect(),
+    };
+    let mut pushed = pushed.into_iter();
+    let res: Vec<Status> = res
+        .into_iter()
+        // unwrap: one pushed status per accepted sharetoken
+        .map(|r| r.unwrap_or_else(|| pushed.next().unwrap()))
+        .collect();
+    Ok(Json(res))
+}
+
 pub async fn withdraw_post_handler(
     State(st): crate::state::Safe,
-    rbody: Result<HeaderSignedJson<WithdrawalRequest>, Json<Status>>,
+    rbody: Result<HeaderSignedJson<KeyedWithdrawalRequest>, Json<Status>>,
 ) -> axum::response::Result<Json<Withdrawal>, Json<Status>> {
     debug!("Entered /withdraw handler.");
     // TODO FIXME validation and error reporting needs to be generalized!
     match rbody {
         Ok(hsj) => {
             debug!("/withdraw body is OK");
-            let st = st.write().await;
-            if st.public.defined.payout.ps_type == hsj.data.w_type {
-                let wrs = serde_json::to_string(&hsj.data).map_err(|e| {
-                    Json(Status {
-                        code: 500,
-                        desc: e.to_string(),
-                    })
-                })?;
-
-                let req = Request::builder()
-                    .method(Method::POST)
-                    .uri(st.public.defined.payout.endpoint.to_string())
-                    .body(Body::from(wrs))
-                    .expect("request builder");
+            // never hold a lock on the state across payment system requests
+            let (payments, tracker, pending) = {
+                let st = st.read().await;
+                (st.payments.clone(), st.tracker.clone(), st.pending.clone())
+            };
+            let KeyedWithdrawalRequest {
+                request: wr,
+                idempotency_key: key,
+            } = hsj.data;
+            if let Some(ps) = payments.get(&wr.w_type) {
+                let payout = ps.cfg();
+                if let Some(min) = payout.min_withdrawal.filter(|min| wr.amount < *min) {
+                    return Err(Json(Status {
+                        code: 400,
+                        desc: format!("withdrawal amount below minimum of {}", min),
+                    }));
+                }
+                if let Some(max) = payout.max_withdrawal.filter(|max| wr.amount > *max) {
+                    return Err(Json(Status {
+                        code: 400,
+                        desc: format!("withdrawal amount above maximum of {}", max),
+                    }));
+                }
 
                 let rk = hsj.public_key.to_string();
 
-                st.tracker
-                    .write()
-                    .await
-                    .balances
-                    .draft(&rk, -Decimal::from(hsj.data.amount))
-                    .await
-                    .map_err(|e| {
-                        Json(Status {
-                            code: 500,
-                            desc: e.to_string(),
-                        })
-                    })?;
-                // TODO reuse httpclient
-                let res = HttpClient::new().request(req).await.map_err({
-                    |e| {
-                        Json(Status {
-                            code: 500,
-                            desc: format!(
-                                "could not perform payment system request: {}",
-                                e.to_string()
-                            ),
-                        })
+                let retried = {
+                    let mut tracker = tracker.write().await;
+                    let prior = key
+                        .as_deref()
+                        .and_then(|key| tracker.keyed(&rk, key))
+                        .cloned();
+                    match prior {
+                        Some(Some(w)) => Some(w),
+                        Some(None) => {
+                            return Err(Json(Status {
+                                code: 409,
+                                desc: "withdrawal with this idempotency key in progress"
+                                    .to_string(),
+                            }))
+                        }
+                        None => {
+                            // a retry arriving right after finds the key in progress
+                            tracker
+                                .draft(&rk, -Decimal::from(wr.amount), key.as_deref())
+                                .await
+                                .map_err(|e| {
+                                    Json(Status {
+                                        code: 500,
+                                        desc: e.to_string(),
+                                    })
+                                })?;
+                            None
+                        }
                     }
-                })?;
-                let w: Withdrawal = serde_json::from_slice(
-                    &body::to_bytes(res.into_body()).await.map_err(|e| {
-                        Json(Status {
-                            code: 500,
-                            desc: format!(
-                                "could not get body from payment system: {}",
-                                e.to_string()
-                            ),
-                        })
-                    })?,
-                )
-                .map_err(|e| {
-                    Json(Status {
+                };
+                if let Some(w) = retried {
+                    debug!("/withdraw retried with idempotency key {:?}", key);
+                    // recorded as first confirmed, it may have been resolved since
+                    if watcher::action(&w.state_data.state).is_none() {
+                        match ps.status(&w.id).await {
+                            Ok(w) => return Ok(Json(w)),
+                            Err(e) => debug!("Could not check withdrawal {}: {}", w.id, e),
+                        }
+                    }
+                    return Ok(Json(w));
+                }
+                let pw = PendingWithdrawal::new(&rk, &payout.ps_type, &wr, key.clone());
+                // recorded before submitting, so an unknown outcome can be reconciled
+                let res = pending.lock().await.insert(pw.clone()).await;
+                // no failure may leave the drafted change pending
+                if let Err(e) = res {
+                    debug!("/withdraw failed, aborting drafted balance change: {}", e);
+                    let mut tracker = tracker.write().await;
+                    if let Err(e) = tracker.commit(&rk, Action::Abort).await {
+                        warn!("Could not abort drafted balance change: {}", e);
+                    }
+                    if let Some(key) = &key {
+                        if let Err(e) = tracker.unset_keyed(&rk, key).await {
+                            warn!("Could not forget idempotency key: {}", e);
+                        }
+                    }
+                    return Err(Json(Status {
                         code: 500,
-                        desc: format!("could not get body from payment system: {}", e.to_string()),
-                    })
-                })?;
-                if w.state_data.state == WithdrawalState::Pending {
-                    st.watcher_tx.send(w.clone()).await.map_err(|e| {
-                        Json(Status {
-                            code: 500,
-                            desc: format!(
-                                "could not send pending withdrawal to watcher: {}",
-                                e.to_string()
-                            ),
-                        })
-                    })?;
+                        desc: format!("could not record pending withdrawal: {}", e),
+                    }));
+                }
+                let w = match ps.submit(&pw.key, &wr).await {
+                    Ok(w) => w,
+                    Err(PsError::Failed(e)) => {
+                        debug!("/withdraw rejected, aborting drafted balance change: {}", e);
+                        if let Err(e) = watcher::reject(&pending, &tracker, &pw).await {
+                            warn!(
+                                "Could not abort withdrawal {}, left to the watcher: {}",
+                                pw.key, e
+                            );
+                        }
+                        return Err(Json(Status { code: 500, desc: e }));
+                    }
+                    Err(PsError::Unknown(e)) => {
+                        // it may have gone through, so the drafted change must stay pending
+                        warn!(
+                            "Outcome of withdrawal {} unknown, left to the watcher: {}",
+                            pw.key, e
+                        );
+                        return Err(Json(Status {
+                            code: 202,
+                            desc: "withdrawal submitted, outcome not known yet, being reconciled"
+                                .to_string(),
+                        }));
+                    }
                 };
+                if let Err(e) = watcher::confirm(&pending, &tracker, &pw, &w).await {
+                    warn!(
+                        "Could not record withdrawal {}, left to the watcher: {}",
+                        w.id, e
+                    );
+                }
+
                 Ok(Json(w))
             } else {
                 Err(Json(Status {
@@ -191,25 +442,62 @@ pub async fn withdraw_post_handler(
     }
 }
 
+/// Header a client states the API version it understands with. Absent means version 1.
+pub const API_VERSION_HEADER: &'static str = &"wireleap-api-version";
+
+/// The API version requested by the client, if supported.
+fn api_version(headers: &HeaderMap) -> Result<u16, Json<Status>> {
+    let v = match headers.get(API_VERSION_HEADER) {
+        None => return Ok(1),
+        Some(v) => v.to_str().ok().and_then(|v| v.trim().parse::<u16>().ok()),
+    };
+    match v {
+        Some(v @ 1..=2) => Ok(v),
+        _ => Err(Json(Status {
+            code: 400,
+            desc: "unsupported api version, supported: 1, 2".to_string(),
+        })),
+    }
+}
+
 pub async fn balance_get_handler(
     State(st): crate::state::Safe,
+    headers: HeaderMap,
     rbody: Result<HeaderSignedJson<String>, Json<Status>>,
-) -> axum::response::Result<Json<BalanceView>, Json<Status>> {
+) -> axum::response::Result<Response, Json<Status>> {
     debug!("Entered /payout/balance handler.");
+    let ver = api_version(&headers)?;
     match rbody {
         Ok(hsj) => {
             debug!("/payout/balance body is OK");
-            let st = st.read().await;
-            let tracker = st.tracker.read().await;
-            tracker
+            let (tracker, currency) = {
+                let st = st.read().await;
+                (
+                    st.tracker.clone(),
+                    st.public.defined.servicekey.currency.clone(),
+                )
+            };
+            let bal = tracker
+                .read()
+                .await
                 .balances
-                .get(&hsj.public_key.to_string())
+                .get(&hsj.public_key.to_string(), &currency)
                 .await
                 .ok_or(Json(Status {
                     code: 400,
                     desc: "no such relay".to_string(),
-                }))
-                .map(Json)
+                }))?;
+            let mut res = match ver {
+                1 => Json(
+                    BalanceViewV1::try_from(bal)
+                        .map_err(|e| Json(Status { code: 500, desc: e }))?,
+                )
+                .into_response(),
+                _ => Json(bal).into_response(),
+            };
+            res.headers_mut()
+                .insert(API_VERSION_HEADER, HeaderValue::from(ver));
+            Ok(res)
         }
         Err(e) => {
             debug!("/payout/balance body is NOT OK: {:?}", e);
@@ -217,3 +505,209 @@ pub async fn balance_get_handler(
         }
     }
 }
+
+pub async fn receipts_get_handler(
+    State(st): crate::state::Safe,
+    query: Result<Query<ReceiptsQuery>, QueryRejection>,
+    rbody: Result<HeaderSignedJson<String>, Json<Status>>,
+) -> axum::response::Result<Json<Vec<Receipt>>, Json<Status>> {
+    debug!("Entered /payout/receipts handler.");
+    let Query(q) = query.map_err(|e| {
+        Json(Status {
+            code: 400,
+            desc: e.to_string(),
+        })
+    })?;
+    match rbody {
+        Ok(hsj) => {
+            debug!("/payout/receipts body is OK");
+            let tracker = st.read().await.tracker.clone();
+            let dir = tracker.read().await.receipts_dir();
+            receipts::read(&dir, &hsj.public_key.to_string(), q.since)
+                .await
+                .map(Json)
+                .map_err(|e| {
+                    Json(Status {
+                        code: 500,
+                        desc: format!("could not read receipts: {}", e),
+                    })
+                })
+        }
+        Err(e) => {
+            debug!("/payout/receipts body is NOT OK: {:?}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Statement entries returned per page by default and at most.
+const STATEMENT_PAGE: usize = 100;
+const STATEMENT_PAGE_MAX: usize = 1000;
+
+/// Seconds of history a statement covers by default and at most, bounding the log scanned for it.
+const STATEMENT_WINDOW: i64 = 30 * 24 * 3600;
+const STATEMENT_WINDOW_MAX: i64 = 366 * 24 * 3600;
+
+pub async fn statement_get_handler(
+    State(st): crate::state::Safe,
+    query: Result<Query<StatementQuery>, QueryRejection>,
+    rbody: Result<HeaderSignedJson<String>, Json<Status>>,
+) -> axum::response::Result<Json<Statement>, Json<Status>> {
+    debug!("Entered /payout/statement handler.");
+    let Query(q) = query.map_err(|e| {
+        Json(Status {
+            code: 400,
+            desc: e.to_string(),
+        })
+    })?;
+    match rbody {
+        Ok(hsj) => {
+            debug!("/payout/statement body is OK");
+            let rk = hsj.public_key.to_string();
+            let now = utime(SystemTime::now());
+            let since = q.since.unwrap_or(now - STATEMENT_WINDOW);
+            if since < now - STATEMENT_WINDOW_MAX {
+                return Err(Json(Status {
+                    code: 400,
+                    desc: format!(
+                        "statements go back at most {} days",
+                        STATEMENT_WINDOW_MAX / (24 * 3600)
+                    ),
+                }));
+            }
+            let (tracker, currency) = {
+                let st = st.read().await;
+                (
+                    st.tracker.clone(),
+                    st.public.defined.servicekey.currency.clone(),
+                )
+            };
+            let (dir, end, current) = {
+                // the balance must not change before the log end is taken, so both agree
+                let mut tracker = tracker.write().await;
+                let end = tracker.log_end().await.map_err(|e| {
+                    Json(Status {
+                        code: 500,
+                        desc: format!("could not save tracker log: {}", e),
+                    })
+                })?;
+                (
+                    tracker.log_dir(),
+                    end,
+                    tracker.balances.available(&rk).await,
+                )
+            };
+            let current = current.ok_or(Json(Status {
+                code: 400,
+                desc: "no such relay".to_string(),
+            }))?;
+            let (opening, entries) = tokio::task::spawn_blocking(move || {
+                let events = EventReader::new(&dir, since, i64::MAX)?.until(end);
+                statement::build(events, &rk, current)
+            })
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|r| r.map_err(|e| e.to_string()))
+            .map_err(|e| {
+                Json(Status {
+                    code: 500,
+                    desc: format!("could not read tracker log: {}", e),
+                })
+            })?;
+            let limit = q.limit.unwrap_or(STATEMENT_PAGE).min(STATEMENT_PAGE_MAX);
+            Ok(Json(Statement {
+                currency,
+                since,
+                opening,
+                total: entries.len(),
+                offset: q.offset,
+                entries: entries.into_iter().skip(q.offset).take(limit).collect(),
+            }))
+        }
+        Err(e) => {
+            debug!("/payout/statement body is NOT OK: {:?}", e);
+            Err(e)
+        }
+    }
+}
+
+pub async fn withdrawal_state_post_handler(
+    State(st): crate::state::Safe,
+    rbody: Result<HeaderSignedJson<WithdrawalStateUpdate>, Json<Status>>,
+) -> Json<Status> {
+    debug!("Entered /payout/withdrawal-state handler.");
+    let hsj = match rbody {
+        Ok(hsj) => hsj,
+        Err(e) => {
+            debug!("/payout/withdrawal-state body is NOT OK: {:?}", e);
+            return e;
+        }
+    };
+    let (payments, pending, txn_tx) = {
+        let st = st.read().await;
+        (st.payments.clone(), st.pending.clone(), st.txn_tx.clone())
+    };
+    let signed_by = |ps_type: &str| {
+        payments
+            .get(ps_type)
+            .and_then(|ps| ps.cfg().public_key.as_ref())
+            .map_or(false, |pk| *pk == hsj.public_key)
+    };
+    let signer = payments
+        .all()
+        .map(|ps| ps.cfg().ps_type.as_str())
+        .find(|t| signed_by(t));
+    if !matches!(hsj.signatory, Signatory::Payment) || signer.is_none() {
+        return Json(Status {
+            code: 403,
+            desc: "not signed by a payment system".to_string(),
+        });
+    }
+    let upd = hsj.data;
+    let res = {
+        let mut pending = pending.lock().await;
+        if let Some(pw) = pending.get(&upd.id) {
+            if !signed_by(&pw.ps_type) {
+                return Json(Status {
+                    code: 403,
+                    desc: "withdrawal not handled by this payment system".to_string(),
+                });
+            }
+        }
+        pending.resolve(&upd.id, &upd.state).await
+    };
+    debug!("Withdrawal {} state update: {:?}", upd.id, res);
+    let res = match res {
+        Ok(res) => res,
+        Err(e) => {
+            return Json(Status {
+                code: 500,
+                desc: format!("could not record withdrawal state: {}", e),
+            })
+        }
+    };
+    match res {
+        Resolution::Unknown => Json(Status {
+            code: 404,
+            desc: "no such withdrawal".to_string(),
+        }),
+        Resolution::AlreadyFinal(_) => Json(Status {
+            code: 409,
+            desc: "withdrawal is already final".to_string(),
+        }),
+        Resolution::StillPending => Json(Status {
+            code: 200,
+            desc: "OK".to_string(),
+        }),
+        Resolution::Final(pw, act) => {
+            // recorded as final already, the watcher sends it again if this fails
+            if let Err(e) = txn_tx.send(pw.update(act)).await {
+                warn!("Could not send balance update: {}", e);
+            }
+            Json(Status {
+                code: 200,
+                desc: "OK".to_string(),
+            })
+        }
+    }
+}
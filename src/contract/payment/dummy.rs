@@ -0,0 +1,67 @@
+use super::{PaymentSystem, PsError};
+use crate::api::PayoutCfg;
+use async_trait::async_trait;
+use serde_json::json;
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+use ws_common::api::{Withdrawal, WithdrawalRequest, WithdrawalState};
+
+/// In-process payment system for testing which completes every withdrawal right away. Only used
+/// for payout methods explicitly configured with the dummy backend.
+pub struct Dummy {
+    cfg: PayoutCfg,
+    /// All withdrawals made, by id.
+    ws: Mutex<HashMap<String, Withdrawal>>,
+}
+
+impl Dummy {
+    pub fn new(cfg: PayoutCfg) -> Self {
+        Dummy {
+            cfg,
+            ws: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl PaymentSystem for Dummy {
+    fn cfg(&self) -> &PayoutCfg {
+        &self.cfg
+    }
+
+    async fn submit(&self, key: &str, wr: &WithdrawalRequest) -> Result<Withdrawal, PsError> {
+        // one withdrawal per submission key
+        let id = format!("dummy-{}", key);
+        let mut ws = self.ws.lock().await;
+        if let Some(w) = ws.get(&id) {
+            return Ok(w.clone());
+        }
+        // a withdrawal is the request it was made for plus its id and state
+        let mut w = serde_json::to_value(wr).map_err(|e| PsError::Failed(e.to_string()))?;
+        let state = serde_json::to_value(WithdrawalState::Complete)
+            .map_err(|e| PsError::Failed(e.to_string()))?;
+        w["id"] = json!(id);
+        w["state_data"] = json!({ "state": state });
+        let w: Withdrawal = serde_json::from_value(w)
+            .map_err(|e| PsError::Failed(format!("could not decode withdrawal: {}", e)))?;
+        ws.insert(id, w.clone());
+        Ok(w)
+    }
+
+    async fn status(&self, id: &str) -> Result<Withdrawal, PsError> {
+        match self.ws.lock().await.get(id) {
+            Some(w) => Ok(w.clone()),
+            None => Err(PsError::Failed(format!("no such withdrawal: {}", id))),
+        }
+    }
+
+    async fn cancel(&self, id: &str) -> Result<Withdrawal, PsError> {
+        match self.ws.lock().await.get(id) {
+            Some(_) => Err(PsError::Failed(format!(
+                "withdrawal {} is already final",
+                id
+            ))),
+            None => Err(PsError::Failed(format!("no such withdrawal: {}", id))),
+        }
+    }
+}
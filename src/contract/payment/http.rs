@@ -0,0 +1,206 @@
+use super::{PaymentSystem, PsError};
+use crate::api::{PayoutCfg, TlsCfg};
+use async_trait::async_trait;
+use hyper::{body, client::HttpConnector, Body, Client, Method, Request};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use log::debug;
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore};
+use rustls_pemfile::Item;
+use std::{fs::File, io::BufReader, path::Path};
+use ws_common::api::{Withdrawal, WithdrawalRequest};
+
+/// Payment system reachable over HTTP with JSON bodies. Withdrawals are submitted by POSTing to
+/// the endpoint, their state is read with a GET and they are cancelled with a DELETE of
+/// `<endpoint>/<id>`. Submissions carry their key in an `Idempotency-Key` header, the payment
+/// system must return the same withdrawal for every submission with the same key.
+pub struct Http {
+    cfg: PayoutCfg,
+    /// Pooled client, shared by all requests to this payment system.
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+fn pem_certs(path: &Path) -> Result<Vec<Certificate>, String> {
+    let f = File::open(path).map_err(|e| format!("{}: {}", path.to_string_lossy(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(f))
+        .map_err(|e| format!("{}: {}", path.to_string_lossy(), e))?;
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn pem_key(path: &Path) -> Result<PrivateKey, String> {
+    let f = File::open(path).map_err(|e| format!("{}: {}", path.to_string_lossy(), e))?;
+    rustls_pemfile::read_all(&mut BufReader::new(f))
+        .map_err(|e| format!("{}: {}", path.to_string_lossy(), e))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(k) | Item::RSAKey(k) | Item::ECKey(k) => Some(PrivateKey(k)),
+            _ => None,
+        })
+        .ok_or(format!("{}: no private key found", path.to_string_lossy()))
+}
+
+fn tls_config(cfg: &TlsCfg) -> Result<ClientConfig, String> {
+    let mut roots = RootCertStore::empty();
+    match &cfg.ca_file {
+        Some(ca) => {
+            for c in pem_certs(ca)? {
+                roots.add(&c).map_err(|e| e.to_string())?;
+            }
+        }
+        None => {
+            let certs = rustls_native_certs::load_native_certs()
+                .map_err(|e| format!("could not load system certificates: {}", e))?;
+            for c in certs {
+                // unusable system certificates are not our problem
+                let _ = roots.add(&Certificate(c.0));
+            }
+        }
+    }
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    match (&cfg.cert_file, &cfg.key_file) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(pem_certs(cert)?, pem_key(key)?)
+            .map_err(|e| e.to_string()),
+        (None, None) => Ok(builder.with_no_client_auth()),
+        _ => Err("client certificate and key must be given together".to_string()),
+    }
+}
+
+impl Http {
+    pub fn new(cfg: PayoutCfg) -> Result<Self, String> {
+        let tls = tls_config(&cfg.http.tls)
+            .map_err(|e| format!("payout method {} tls: {}", cfg.ps_type, e))?;
+        let mut conn = HttpConnector::new();
+        conn.set_connect_timeout(Some(cfg.http.connect_timeout));
+        conn.enforce_http(false);
+        let conn = HttpsConnectorBuilder::new()
+            .with_tls_config(tls)
+            .https_or_http()
+            .enable_http1()
+            .enable_http2()
+            .wrap_connector(conn);
+        Ok(Http {
+            cfg,
+            client: Client::builder().build(conn),
+        })
+    }
+
+    fn url(&self, id: &str) -> String {
+        format!(
+            "{}/{}",
+            self.cfg.endpoint.as_str().trim_end_matches('/'),
+            id
+        )
+    }
+
+    /// Perform a request. Failures after the request may have reached the payment system are
+    /// `PsError::Unknown`, unless it told us it rejected the request.
+    async fn request_once(&self, req: Request<Body>) -> Result<Withdrawal, PsError> {
+        let res = self.client.request(req).await.map_err(|e| {
+            let msg = format!("could not perform payment system request: {}", e);
+            // nothing was sent if no connection could be made
+            match e.is_connect() {
+                true => PsError::Failed(msg),
+                false => PsError::Unknown(msg),
+            }
+        })?;
+        let status = res.status();
+        let b = body::to_bytes(res.into_body()).await.map_err(|e| {
+            PsError::Unknown(format!("could not get body from payment system: {}", e))
+        })?;
+        if !status.is_success() {
+            let e = format!(
+                "payment system returned {}: {}",
+                status,
+                String::from_utf8_lossy(&b)
+            );
+            return Err(match status.is_client_error() {
+                true => PsError::Failed(e),
+                false => PsError::Unknown(e),
+            });
+        }
+        serde_json::from_slice(&b)
+            .map_err(|e| PsError::Unknown(format!("could not decode withdrawal: {}", e)))
+    }
+
+    /// Perform a request within the configured timeout. Idempotent requests with an unknown
+    /// outcome are retried with exponential backoff, so `mkreq` is called for every attempt.
+    /// Definitive failures are returned right away, retrying them would fail the same way.
+    async fn request<F>(&self, mkreq: F, idempotent: bool) -> Result<Withdrawal, PsError>
+    where
+        F: Fn() -> Request<Body>,
+    {
+        let http = &self.cfg.http;
+        let retries = if idempotent { http.retries } else { 0 };
+        let mut delay = http.backoff;
+        let mut attempt = 0;
+        loop {
+            let res = match tokio::time::timeout(http.request_timeout, self.request_once(mkreq()))
+                .await
+            {
+                Ok(res) => res,
+                // the request may have been sent already
+                Err(_) => Err(PsError::Unknown(format!(
+                    "payment system request timed out after {:?}",
+                    http.request_timeout
+                ))),
+            };
+            match res {
+                Err(PsError::Unknown(e)) if attempt < retries => {
+                    attempt += 1;
+                    debug!(
+                        "Payment system request outcome unknown, retry {}/{} in {:?}: {}",
+                        attempt, retries, delay, e
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl PaymentSystem for Http {
+    fn cfg(&self) -> &PayoutCfg {
+        &self.cfg
+    }
+
+    async fn submit(&self, key: &str, wr: &WithdrawalRequest) -> Result<Withdrawal, PsError> {
+        let wrs = serde_json::to_string(wr).map_err(|e| PsError::Failed(e.to_string()))?;
+        let mkreq = || {
+            Request::builder()
+                .method(Method::POST)
+                .uri(self.cfg.endpoint.to_string())
+                .header("Idempotency-Key", key)
+                .body(Body::from(wrs.clone()))
+                .expect("request builder")
+        };
+        // submissions with an unknown outcome are made again by the watcher, under the same key
+        self.request(mkreq, false).await
+    }
+
+    async fn status(&self, id: &str) -> Result<Withdrawal, PsError> {
+        let mkreq = || {
+            Request::builder()
+                .method(Method::GET)
+                .uri(self.url(id))
+                .body(Body::empty())
+                .expect("request builder")
+        };
+        self.request(mkreq, true).await
+    }
+
+    async fn cancel(&self, id: &str) -> Result<Withdrawal, PsError> {
+        let mkreq = || {
+            Request::builder()
+                .method(Method::DELETE)
+                .uri(self.url(id))
+                .body(Body::empty())
+                .expect("request builder")
+        };
+        self.request(mkreq, true).await
+    }
+}
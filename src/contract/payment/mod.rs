@@ -0,0 +1,84 @@
+use crate::api::{Backend, PayoutCfg};
+use async_trait::async_trait;
+use log::warn;
+use std::{collections::HashMap, fmt, sync::Arc};
+use ws_common::api::{Withdrawal, WithdrawalRequest};
+
+pub mod dummy;
+pub mod http;
+
+/// Reasons for a payment system request to fail.
+#[derive(Debug)]
+pub enum PsError {
+    /// The request had no effect, e.g. as it could not be sent or was rejected.
+    Failed(String),
+    /// The request may or may not have had an effect, e.g. as it timed out after being sent.
+    Unknown(String),
+}
+
+impl fmt::Display for PsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use PsError::*;
+        match self {
+            Failed(e) => write!(f, "{}", e),
+            Unknown(e) => write!(f, "outcome unknown: {}", e),
+        }
+    }
+}
+
+/// A payment system withdrawals are paid out through.
+#[async_trait]
+pub trait PaymentSystem: Send + Sync {
+    /// The payout method configuration of this payment system.
+    fn cfg(&self) -> &PayoutCfg;
+
+    /// Submit a withdrawal request, returning the resulting withdrawal. Submissions with the same
+    /// `key` result in a single withdrawal, so a submission with an unknown outcome can be made
+    /// again.
+    async fn submit(&self, key: &str, wr: &WithdrawalRequest) -> Result<Withdrawal, PsError>;
+
+    /// Get the current state of a withdrawal.
+    async fn status(&self, id: &str) -> Result<Withdrawal, PsError>;
+
+    /// Cancel a withdrawal which is not final yet.
+    async fn cancel(&self, id: &str) -> Result<Withdrawal, PsError>;
+}
+
+pub type SafePaymentSystem = Arc<dyn PaymentSystem>;
+
+/// The configured payment systems, by payout method type.
+pub struct Registry {
+    h: HashMap<String, SafePaymentSystem>,
+}
+
+impl Registry {
+    /// Sets up a payment system for the primary payout method and every one in `rest`.
+    pub fn new(primary: &PayoutCfg, rest: &[PayoutCfg]) -> Result<Self, String> {
+        let mut h = HashMap::new();
+        for cfg in std::iter::once(primary).chain(rest) {
+            let ps: SafePaymentSystem = match cfg.backend {
+                Backend::Http => Arc::new(http::Http::new(cfg.clone())?),
+                Backend::Dummy => {
+                    warn!(
+                        "Payout method {} uses the dummy backend, withdrawals are NOT paid out!",
+                        cfg.ps_type
+                    );
+                    Arc::new(dummy::Dummy::new(cfg.clone()))
+                }
+            };
+            if h.insert(cfg.ps_type.clone(), ps).is_some() {
+                return Err(format!("duplicate payout method type: {}", cfg.ps_type));
+            }
+        }
+        Ok(Registry { h })
+    }
+
+    /// Get the payment system for a payout method type.
+    pub fn get(&self, ps_type: &str) -> Option<&SafePaymentSystem> {
+        self.h.get(ps_type)
+    }
+
+    pub fn all(&self) -> impl Iterator<Item = &SafePaymentSystem> {
+        self.h.values()
+    }
+}
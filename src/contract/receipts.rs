@@ -0,0 +1,75 @@
+use crate::api::Receipt;
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+
+/// Signed settlement receipts, stored as JSON lines in one file per relay.
+pub struct Receipts {
+    /// Path to the receipts folder on disk. (owned)
+    dir: PathBuf,
+}
+
+fn path(dir: &Path, rk: &str) -> PathBuf {
+    dir.join(rk.to_string() + ".jsonl")
+}
+
+impl Receipts {
+    pub async fn open(dir: PathBuf) -> Result<Self, io::Error> {
+        tokio::fs::create_dir_all(&dir).await?;
+        Ok(Receipts { dir })
+    }
+
+    /// Path to the receipts folder, for use with `read`.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Durably appends receipts to the files of their relays.
+    pub async fn append(&self, rs: &[Receipt]) -> Result<(), io::Error> {
+        let mut lines: HashMap<&str, Vec<u8>> = HashMap::new();
+        for r in rs {
+            let buf = lines.entry(&r.relay).or_default();
+            serde_json::to_writer(&mut *buf, r)?;
+            buf.push(b'\n');
+        }
+        for (rk, buf) in lines {
+            let mut f = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path(&self.dir, rk))
+                .await?;
+            f.write_all(&buf).await?;
+            f.sync_data().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the receipts of relay `rk` for settlements at or after `since`, oldest first. Receipts
+/// written again after a restart are returned only once.
+pub async fn read(dir: &Path, rk: &str, since: i64) -> Result<Vec<Receipt>, io::Error> {
+    let s = match tokio::fs::read_to_string(path(dir, rk)).await {
+        Ok(s) => s,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut rs = Vec::new();
+    let mut sigs = HashSet::new();
+    for line in s.lines() {
+        match serde_json::from_str::<Receipt>(line) {
+            Ok(r) if r.settled_at >= since => {
+                if sigs.insert(r.signature.to_string()) {
+                    rs.push(r)
+                }
+            }
+            Ok(_) => (),
+            // a partially written last line
+            Err(_) if !s.ends_with('\n') => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(rs)
+}
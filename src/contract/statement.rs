@@ -0,0 +1,64 @@
+use super::{eventlog::Event, tracker::Action};
+use crate::api::{EntryState, StatementEntry};
+use rust_decimal::Decimal;
+use std::io;
+
+/// Builds the ledger of relay `rk` from its logged events, oldest first, and returns it along
+/// with the opening balance. As the events need not go back to the very first balance change,
+/// the opening balance is derived from the `current` available balance, which must reflect
+/// exactly the given events.
+pub fn build<I>(
+    events: I,
+    rk: &str,
+    current: Decimal,
+) -> Result<(Decimal, Vec<StatementEntry>), io::Error>
+where
+    I: Iterator<Item = Result<(i64, Event), io::Error>>,
+{
+    let mut entries: Vec<StatementEntry> = Vec::new();
+    // index of the withdrawal awaiting its final state, there is at most one per relay
+    let mut open: Option<usize> = None;
+    for ev in events {
+        match ev? {
+            (t, Event::Distribution(sk, r, delta)) if r == rk => entries.push(StatementEntry {
+                time: t,
+                servicekey: Some(sk),
+                state: None,
+                amount: delta,
+                balance: Decimal::ZERO,
+            }),
+            (t, Event::WithdrawalPending(r, delta)) if r == rk => {
+                open = Some(entries.len());
+                entries.push(StatementEntry {
+                    time: t,
+                    servicekey: None,
+                    state: Some(EntryState::Pending),
+                    amount: delta,
+                    balance: Decimal::ZERO,
+                })
+            }
+            (_, Event::WithdrawalFinal(r, act)) if r == rk => {
+                if let Some(i) = open.take() {
+                    entries[i].state = Some(match act {
+                        Action::Apply => EntryState::Complete,
+                        Action::Abort => EntryState::Aborted,
+                    })
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // only distributions and complete withdrawals change the available balance
+    let counts = |e: &StatementEntry| e.state.map_or(true, |s| s == EntryState::Complete);
+    let net: Decimal = entries.iter().filter(|e| counts(e)).map(|e| e.amount).sum();
+    let opening = current - net;
+    let mut balance = opening;
+    for e in entries.iter_mut() {
+        if counts(e) {
+            balance += e.amount;
+        }
+        e.balance = balance;
+    }
+    Ok((opening, entries))
+}
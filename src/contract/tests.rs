@@ -0,0 +1,551 @@
+//! Withdrawal tests against a local stub payment system, reached through the HTTP backend.
+
+use super::{
+    calc::DefaultShareCalc,
+    journal::{Journal, Snapshot},
+    payment::Registry,
+    tracker::{BalanceUpdate, Tracker},
+    watcher::{self, Pending},
+};
+use crate::{
+    api::{
+        headersignedjson::{HeaderSignedJson, Signatory},
+        signed::Signed,
+        Backend, HttpCfg, KeyedWithdrawalRequest, LogCfg, PayoutCfg, PubDefined, SKContract,
+        Sharetoken,
+    },
+    cfg, directory, state,
+};
+use axum::{extract::State, response::IntoResponse, Json};
+use ed25519_dalek::{Signer, SigningKey};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server,
+};
+use rand::rngs::OsRng;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc, Mutex, RwLock};
+use url::Url;
+use ws_common::{
+    api::{Status, Withdrawal, WithdrawalRequest, WithdrawalState},
+    b64e::Base64,
+    time::utimenow,
+};
+
+/// How the stub payment system answers submissions.
+#[derive(Clone)]
+enum Reply {
+    /// Accept, reporting new withdrawals in the contained state.
+    Accept(WithdrawalState),
+    /// Reject with the contained HTTP status.
+    Reject(u16),
+}
+
+/// Payment system stub deduplicating submissions by their `Idempotency-Key`, like a real one must.
+#[derive(Clone)]
+struct Stub {
+    reply: Arc<StdMutex<Reply>>,
+    /// Delay before answering any request.
+    delay: Arc<StdMutex<Duration>>,
+    /// Withdrawals by id.
+    ws: Arc<StdMutex<HashMap<String, Value>>>,
+}
+
+impl Stub {
+    fn new(reply: Reply) -> Self {
+        Stub {
+            reply: Arc::new(StdMutex::new(reply)),
+            delay: Arc::new(StdMutex::new(Duration::ZERO)),
+            ws: Arc::new(StdMutex::new(HashMap::new())),
+        }
+    }
+
+    fn reply(&self, reply: Reply) {
+        *self.reply.lock().unwrap() = reply;
+    }
+
+    fn delay(&self, delay: Duration) {
+        *self.delay.lock().unwrap() = delay;
+    }
+
+    /// Move the withdrawal `id` to `state`, as the payment system would on processing it.
+    fn set_state(&self, id: &str, state: WithdrawalState) {
+        let state = serde_json::to_value(state).unwrap();
+        self.ws.lock().unwrap().get_mut(id).unwrap()["state_data"] = json!({ "state": state });
+    }
+
+    fn withdrawals(&self) -> usize {
+        self.ws.lock().unwrap().len()
+    }
+
+    async fn handle(self, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let delay = *self.delay.lock().unwrap();
+        tokio::time::sleep(delay).await;
+        let key = req
+            .headers()
+            .get("Idempotency-Key")
+            .and_then(|k| k.to_str().ok())
+            .map(str::to_string);
+        let (code, body) = match (req.method().clone(), key) {
+            (Method::POST, Some(key)) => {
+                let b = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                let reply = self.reply.lock().unwrap().clone();
+                match reply {
+                    Reply::Reject(code) => (code, json!({ "error": "rejected" })),
+                    Reply::Accept(state) => {
+                        let id = format!("stub-{}", key);
+                        let mut ws = self.ws.lock().unwrap();
+                        let w = ws.entry(id.clone()).or_insert_with(|| {
+                            let mut w: Value = serde_json::from_slice(&b).unwrap();
+                            w["id"] = json!(id);
+                            w["state_data"] = json!({ "state": state });
+                            w
+                        });
+                        (200, w.clone())
+                    }
+                }
+            }
+            (Method::GET, _) => {
+                let id = req.uri().path().rsplit('/').next().unwrap_or_default();
+                match self.ws.lock().unwrap().get(id) {
+                    Some(w) => (200, w.clone()),
+                    None => (404, json!({ "error": "no such withdrawal" })),
+                }
+            }
+            _ => (400, json!({ "error": "bad request" })),
+        };
+        Ok(Response::builder()
+            .status(code)
+            .body(Body::from(body.to_string()))
+            .unwrap())
+    }
+
+    /// Serve on an ephemeral local port, returning the withdrawals endpoint.
+    async fn serve(&self) -> Url {
+        let stub = self.clone();
+        let make = make_service_fn(move |_| {
+            let stub = stub.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| stub.clone().handle(req))) }
+        });
+        let srv = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make);
+        let url = Url::parse(&format!("http://{}/withdrawals", srv.local_addr())).unwrap();
+        tokio::spawn(srv);
+        url
+    }
+}
+
+/// A contract paying out through a stub payment system, with a single funded relay.
+struct Contract {
+    root: PathBuf,
+    st: crate::state::Safe,
+    stub: Stub,
+    relay: SigningKey,
+}
+
+/// Balance of the relay to begin with, as if earned in earlier settlements.
+const FUNDS: Decimal = dec!(1000);
+
+fn payout(endpoint: Url, request_timeout: Duration) -> PayoutCfg {
+    PayoutCfg {
+        endpoint,
+        ps_type: "stub".to_string(),
+        backend: Backend::Http,
+        check_period: Duration::from_millis(50),
+        min_withdrawal: None,
+        max_withdrawal: None,
+        info: None,
+        public_key: None,
+        http: HttpCfg {
+            request_timeout,
+            retries: 0,
+            ..HttpCfg::default()
+        },
+    }
+}
+
+async fn tracker(root: &Path) -> (Tracker, mpsc::Sender<BalanceUpdate>) {
+    let calc = DefaultShareCalc {
+        value: dec!(100),
+        fee_frac: dec!(0.05),
+        rsh_frac: dec!(0.05),
+    };
+    let (txn_tx, txn_rx) = mpsc::channel(100);
+    let tracker = Tracker::new(
+        root.to_path_buf(),
+        Arc::new(Box::new(calc)),
+        5,
+        txn_rx,
+        LogCfg::default(),
+        Duration::from_secs(3600),
+        SigningKey::generate(&mut OsRng),
+    )
+    .await
+    .unwrap();
+    (tracker, txn_tx)
+}
+
+impl Contract {
+    async fn new(reply: Reply, request_timeout: Duration) -> Self {
+        let root =
+            std::env::temp_dir().join(format!("contract-test-{:016x}", rand::random::<u64>()));
+        tokio::fs::create_dir_all(&root).await.unwrap();
+        let relay = SigningKey::generate(&mut OsRng);
+        let rk = Base64(relay.verifying_key()).to_string();
+        let (mut journal, _, _) = Journal::open(&root).await.unwrap();
+        journal
+            .compact(Snapshot {
+                balances: HashMap::from([(rk, (FUNDS, Decimal::ZERO))]),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        drop(journal);
+
+        let stub = Stub::new(reply);
+        let payout = payout(stub.serve().await, request_timeout);
+        let kp = SigningKey::generate(&mut OsRng);
+        let (tracker, txn_tx) = tracker(&root).await;
+        let custom = state::Custom {
+            directory: directory::RelayStore::load(&root).await.unwrap(),
+            public: cfg::mkpublic(
+                PubDefined {
+                    payout: payout.clone(),
+                    ..PubDefined::default()
+                },
+                kp.verifying_key(),
+            ),
+            tracker: Arc::new(RwLock::new(tracker)),
+            txn_tx,
+            pending: Arc::new(Mutex::new(
+                Pending::load(&root, Duration::from_secs(3600))
+                    .await
+                    .unwrap(),
+            )),
+            payments: Arc::new(Registry::new(&payout, &[]).unwrap()),
+        };
+        let st = State(ws_common::state::new(kp, Arc::new(RwLock::new(custom))));
+        Contract {
+            root,
+            st,
+            stub,
+            relay,
+        }
+    }
+
+    fn rk(&self) -> String {
+        Base64(self.relay.verifying_key()).to_string()
+    }
+
+    /// Watch pending withdrawals in the background, like the running contract does.
+    fn watch(&self) {
+        let st = self.st.clone();
+        tokio::spawn(async move {
+            let (pending, payments, tracker, txn_tx) = {
+                let st = st.read().await;
+                (
+                    st.pending.clone(),
+                    st.payments.clone(),
+                    st.tracker.clone(),
+                    st.txn_tx.clone(),
+                )
+            };
+            watcher::run(
+                pending,
+                payments,
+                tracker,
+                txn_tx,
+                Duration::from_millis(50),
+            )
+            .await
+        });
+    }
+
+    /// Request a withdrawal of `amount` by the relay.
+    async fn withdraw(&self, amount: u64, key: Option<&str>) -> Result<Withdrawal, Json<Status>> {
+        let hsj = HeaderSignedJson {
+            signatory: Signatory::Relay,
+            public_key: Base64(self.relay.verifying_key()),
+            // the extractor verifies signatures, handlers are called with verified requests
+            signature: Base64(self.relay.sign(b"")),
+            data: KeyedWithdrawalRequest {
+                request: request(amount),
+                idempotency_key: key.map(str::to_string),
+            },
+        };
+        super::withdraw_post_handler(self.st.clone(), Ok(hsj))
+            .await
+            .map(|Json(w)| w)
+    }
+
+    async fn available(&self) -> Decimal {
+        let tracker = self.st.read().await.tracker.clone();
+        let tracker = tracker.read().await;
+        tracker.balances.available(&self.rk()).await.unwrap()
+    }
+
+    async fn drafted(&self) -> bool {
+        let tracker = self.st.read().await.tracker.clone();
+        let tracker = tracker.read().await;
+        tracker.balances.drafted(&self.rk()).await
+    }
+
+    /// Apply balance updates until the withdrawal `reference` has been finalized, as the tracker
+    /// loop does. Returns false if it is not finalized in time.
+    async fn finalized(&self, reference: &str) -> bool {
+        let tracker = self.st.read().await.tracker.clone();
+        for _ in 0..100 {
+            {
+                let mut tracker = tracker.write().await;
+                tracker.txn_tick().await;
+                if tracker.finished(reference) {
+                    return true;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    async fn pending(&self) -> Vec<watcher::PendingWithdrawal> {
+        self.st.read().await.pending.lock().await.all()
+    }
+}
+
+impl Drop for Contract {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+/// A sharetoken of a fresh servicekey activated by the contract, used at the relay.
+async fn sharetoken(c: &Contract) -> Sharetoken {
+    let sk = SigningKey::generate(&mut OsRng);
+    let now = utimenow();
+    let mut skc = SKContract {
+        public_key: c.st.read().await.public.derived.public_key,
+        signature: Base64([0; 64]),
+        servicekey: Base64(sk.verifying_key()),
+        settlement_open: now,
+        settlement_close: now + 3600,
+    };
+    skc.signature = Base64(c.st.crypto.key.sign(skc.message().as_bytes()).to_bytes());
+    let nonce = format!("{:016x}", rand::random::<u64>());
+    Sharetoken {
+        version: 1,
+        public_key: Base64(sk.verifying_key()),
+        timestamp: now,
+        relay_pubkey: Base64(c.relay.verifying_key()),
+        share_key: String::new(),
+        signature: Base64(sk.sign(nonce.as_bytes()).to_bytes()),
+        nonce,
+        contract: skc,
+    }
+}
+
+/// A withdrawal request to the stub payment system.
+fn request(amount: u64) -> WithdrawalRequest {
+    serde_json::from_value(json!({
+        "type": "stub",
+        "amount": amount,
+        "destination": "test",
+    }))
+    .unwrap()
+}
+
+#[tokio::test]
+async fn completed_withdrawal_applies_once() {
+    let c = Contract::new(
+        Reply::Accept(WithdrawalState::Complete),
+        Duration::from_secs(5),
+    )
+    .await;
+    let w = c.withdraw(100, Some("once")).await.unwrap();
+    assert_eq!(c.available().await, FUNDS - dec!(100));
+    assert!(!c.drafted().await);
+    // retried with the same idempotency key, answered with the original withdrawal
+    let again = c.withdraw(100, Some("once")).await.unwrap();
+    assert_eq!(again.id, w.id);
+    assert_eq!(c.available().await, FUNDS - dec!(100));
+    assert_eq!(c.stub.withdrawals(), 1);
+}
+
+#[tokio::test]
+async fn rejected_withdrawal_unlocks_relay() {
+    let c = Contract::new(Reply::Reject(400), Duration::from_secs(5)).await;
+    assert!(c.withdraw(100, Some("rejected")).await.is_err());
+    assert!(!c.drafted().await);
+    assert!(c.pending().await.is_empty());
+    assert_eq!(c.available().await, FUNDS);
+
+    // the relay can withdraw again, even with the same idempotency key
+    c.stub.reply(Reply::Accept(WithdrawalState::Complete));
+    c.withdraw(100, Some("rejected")).await.unwrap();
+    assert_eq!(c.available().await, FUNDS - dec!(100));
+}
+
+#[tokio::test]
+async fn pending_withdrawal_is_watched() {
+    let c = Contract::new(
+        Reply::Accept(WithdrawalState::Pending),
+        Duration::from_secs(5),
+    )
+    .await;
+    let w = c.withdraw(100, Some("watched")).await.unwrap();
+    assert!(c.drafted().await);
+    let pws = c.pending().await;
+    assert_eq!(pws.len(), 1);
+    assert_eq!(pws[0].id, w.id);
+    // the relay is locked while the withdrawal is pending
+    assert!(c.withdraw(100, None).await.is_err());
+
+    c.watch();
+    c.stub.set_state(&w.id, WithdrawalState::Complete);
+    assert!(c.finalized(pws[0].reference()).await);
+    assert!(!c.drafted().await);
+    assert_eq!(c.available().await, FUNDS - dec!(100));
+    // retries are answered with the current state
+    let again = c.withdraw(100, Some("watched")).await.unwrap();
+    assert_eq!(again.id, w.id);
+    assert!(matches!(again.state_data.state, WithdrawalState::Complete));
+}
+
+#[tokio::test]
+async fn unknown_outcome_keeps_draft_pending() {
+    let c = Contract::new(
+        Reply::Accept(WithdrawalState::Complete),
+        Duration::from_millis(200),
+    )
+    .await;
+    c.stub.delay(Duration::from_secs(1));
+    let res = c.withdraw(100, Some("slow")).await;
+    assert_eq!(res.unwrap_err().0.code, 202);
+    // the submission may have gone through, so the funds stay drafted
+    assert!(c.drafted().await);
+    let pws = c.pending().await;
+    assert_eq!(pws.len(), 1);
+    assert!(pws[0].id.is_empty());
+    // the relay is answered consistently while the outcome is not known
+    assert_eq!(c.withdraw(100, Some("slow")).await.unwrap_err().0.code, 409);
+
+    // submitted again under the same key, which the payment system deduplicates
+    c.stub.delay(Duration::ZERO);
+    c.watch();
+    assert!(c.finalized(pws[0].reference()).await);
+    assert_eq!(c.stub.withdrawals(), 1);
+    assert_eq!(c.available().await, FUNDS - dec!(100));
+    let w = c.withdraw(100, Some("slow")).await.unwrap();
+    assert_eq!(w.id, format!("stub-{}", pws[0].key));
+}
+
+#[tokio::test]
+async fn reconcile_aborts_unrecorded_draft() {
+    let c = Contract::new(
+        Reply::Accept(WithdrawalState::Complete),
+        Duration::from_secs(5),
+    )
+    .await;
+    let rk = c.rk();
+    {
+        // stopped after journaling the draft, before recording the pending withdrawal
+        let tracker = c.st.read().await.tracker.clone();
+        let mut tracker = tracker.write().await;
+        tracker
+            .draft(&rk, dec!(-100), Some("crashed"))
+            .await
+            .unwrap();
+    }
+
+    let (mut tracker, _txn_tx) = tracker(&c.root).await;
+    let pending = Pending::load(&c.root, Duration::from_secs(3600))
+        .await
+        .unwrap();
+    assert!(tracker.balances.drafted(&rk).await);
+    assert_eq!(watcher::reconcile(&pending, &mut tracker).await.unwrap(), 1);
+    assert!(!tracker.balances.drafted(&rk).await);
+    assert_eq!(tracker.balances.available(&rk).await, Some(FUNDS));
+    assert!(tracker.keyed(&rk, "crashed").is_none());
+    // nothing left to do on the next start
+    assert_eq!(watcher::reconcile(&pending, &mut tracker).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn reconcile_keeps_recorded_withdrawal() {
+    let c = Contract::new(
+        Reply::Accept(WithdrawalState::Pending),
+        Duration::from_secs(5),
+    )
+    .await;
+    let rk = c.rk();
+    c.withdraw(100, Some("recorded")).await.unwrap();
+
+    let (mut tracker, _txn_tx) = tracker(&c.root).await;
+    let pending = Pending::load(&c.root, Duration::from_secs(3600))
+        .await
+        .unwrap();
+    assert_eq!(watcher::reconcile(&pending, &mut tracker).await.unwrap(), 0);
+    assert!(tracker.balances.drafted(&rk).await);
+    assert!(matches!(tracker.keyed(&rk, "recorded"), Some(Some(_))));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn slow_payment_system_only_delays_withdraw() {
+    let delay = Duration::from_secs(2);
+    let c = Arc::new(
+        Contract::new(
+            Reply::Accept(WithdrawalState::Complete),
+            Duration::from_secs(10),
+        )
+        .await,
+    );
+    c.stub.delay(delay);
+    let started = Instant::now();
+    let wc = c.clone();
+    let withdrawal = tokio::spawn(async move { wc.withdraw(100, None).await });
+    // let the withdrawal reach the payment system
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!withdrawal.is_finished());
+
+    // concurrent traffic while /withdraw waits for the payment system
+    let mut requests = Vec::new();
+    for _ in 0..20 {
+        let c = c.clone();
+        requests.push(tokio::spawn(async move {
+            let t = Instant::now();
+            directory::info_get_handler(c.st.clone()).await;
+            let info = t.elapsed();
+            let st = Signed(sharetoken(&c).await);
+            let t = Instant::now();
+            let res = super::submit_post_handler(c.st.clone(), Ok(Json(st)))
+                .await
+                .into_response();
+            let submit = t.elapsed();
+            let b = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            let status: Status = serde_json::from_slice(&b).unwrap();
+            assert_eq!(status.code, 200, "{}", status.desc);
+            (info, submit)
+        }));
+    }
+    let (mut info, mut submit) = (Duration::ZERO, Duration::ZERO);
+    for r in requests {
+        let (i, s) = r.await.unwrap();
+        info = info.max(i);
+        submit = submit.max(s);
+    }
+    // blocked behind the withdrawal, they would take about as long as the payment system
+    assert!(info < delay / 2, "/info took up to {:?}", info);
+    assert!(submit < delay / 2, "/submit took up to {:?}", submit);
+    assert!(!withdrawal.is_finished());
+
+    withdrawal.await.unwrap().unwrap();
+    assert!(started.elapsed() >= delay);
+    assert_eq!(c.available().await, FUNDS - dec!(100));
+}
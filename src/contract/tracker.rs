@@ -1,20 +1,31 @@
-use super::calc::SafeCalc;
+use super::{
+    calc::SafeCalc,
+    eventlog::{Event, EventLog, LogEnd},
+    journal::{Journal, Record, Snapshot},
+    receipts::Receipts,
+};
 use crate::{
-    api::signable::Signable,
-    api::{chronosort::ChronoSort, Sharetoken},
+    api::{chronosort::ChronoSort, LogCfg, Receipt, Sharetoken},
+    api::{signable::Signable, signed::Signed},
 };
-use log::debug;
+use ed25519_dalek::{Signer, SigningKey};
+use log::{debug, warn};
 use rust_decimal::{prelude::ToPrimitive, Decimal};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{BinaryHeap, HashMap},
+    collections::{hash_map::Entry, BinaryHeap, HashMap, HashSet},
     error::Error,
     io,
     path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 use tokio::sync::{mpsc::Receiver, RwLock};
-use ws_common::{b64e::Base64, time::utimenow};
+use ws_common::{
+    api::{Pof, Withdrawal},
+    b64e::Base64,
+    time::utimenow,
+};
 
 #[derive(Serialize, Deserialize, Copy, Clone, Debug)]
 pub enum Action {
@@ -24,64 +35,16 @@ pub enum Action {
 
 #[derive(Clone, Debug)]
 pub struct BalanceUpdate {
+    /// Withdrawal reference, the update is applied only once per withdrawal.
+    pub id: String,
     pub relay: String,
     pub action: Action,
 }
 
-/// The enum of all possible tracker log events.
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all = "snake_case")]
-enum Event {
-    // submission of a single sharetoken: servicekey public key, relay public key
-    Submission(String, String),
-    // change in the relay's balance on settlement: servicekey public key, relay public key, delta
-    Distribution(String, String, Decimal),
-    // pending withdrawal: relay public key, delta
-    WithdrawalPending(String, Decimal),
-    // final withdrawal: relay public key, action
-    WithdrawalFinal(String, Action),
-    // settlement of a single servicekey: servicekey public key
-    Settlement(String),
-}
-
-/// Tracker log keeps records of utime and event.
-#[derive(Serialize, Deserialize, Debug)]
-struct TrackerLog {
-    start: i64,
-    events: Vec<(i64, Event)>,
-}
-
-impl TrackerLog {
-    fn new() -> Self {
-        TrackerLog {
-            start: utimenow(),
-            events: Vec::new(),
-        }
-    }
-
-    fn add(&mut self, e: Event) {
-        self.events.push((utimenow(), e))
-    }
-
-    // TODO append?
-    async fn save(&self, p: &Path) -> Result<(), impl Error> {
-        tokio::fs::write(
-            p.join(format!("contract_{}.log", self.start)),
-            serde_json::to_string(self)?,
-        )
-        .await
-        .into()
-    }
-}
-
 /// The tracker keeps track of: Sharetokens, shares (only during settlement), resulting balances.
 pub struct Tracker {
-    /// Path to the tracker root folder on disk. (owned)
-    root_path: PathBuf,
     /// Path to the tracker archive folder on disk. (owned)
     archive_path: PathBuf,
-    /// Path to the tracker unsettled folder on disk. (owned)
-    unsettled_path: PathBuf,
     /// The defined share reward calculation function.
     calc: SafeCalc,
     /// The interval at which to attempt settlement of accumulated Sharetokens.
@@ -91,8 +54,21 @@ pub struct Tracker {
     /// The to-be-settled `Sharetoken` queue. Using a BinaryHeap ensures the Sharetokens are sorted
     /// chronologically.
     sts: BinaryHeap<ChronoSort<Sharetoken>>,
+    /// Filenames (signatures) of submitted `Sharetoken`s mapped to their settlement close, to
+    /// detect resubmission. Entries are dropped on settlement close, as `Sharetoken`s are not
+    /// accepted afterwards anyway.
+    seen: HashMap<String, i64>,
     /// The balances table with actual and pending relay balances.
     pub balances: Balances,
+    /// The registry of pofs already used for servicekey activation.
+    spent: SpentPofs,
+    /// The registry of withdrawals requested with an idempotency key.
+    keyed: KeyedWithdrawals,
+    /// How long to keep entries in the keyed withdrawals registry.
+    key_retention: i64,
+    /// Ids of withdrawals whose balance change has been finalized, mapped to the time it was.
+    /// Kept for `key_retention`, long enough to recognize any repeated balance update.
+    finished: HashMap<String, i64>,
     /// For temporary use during settlement calculation only.
     totals: HashMap<String, Decimal>,
     /// For temporary use during settlement calculation only.
@@ -101,8 +77,16 @@ pub struct Tracker {
     archive_q: Vec<Sharetoken>,
     /// For receiving txn state updates.
     txn_chan: Receiver<BalanceUpdate>,
-    /// Log of this tracker.
-    log: TrackerLog,
+    /// Event log of this tracker.
+    log: EventLog,
+    /// Write-ahead journal of all durable state changes.
+    journal: Journal,
+    /// Signed receipts of all distributions.
+    receipts: Receipts,
+    /// Queue of journaled receipts to be written, which can grow if writing to FS is not possible.
+    receipts_q: Vec<Receipt>,
+    /// The contract key to sign receipts with.
+    key: SigningKey,
 }
 
 /// The balances struct allows threadsafe access to the actual and pending balance of a relay.
@@ -114,13 +98,72 @@ pub struct Balances {
     h: HashMap<String, Arc<RwLock<(Decimal, Decimal)>>>,
 }
 
+/// Exact relay balance, with amounts as decimal strings. Served as of API version 2.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct BalanceView {
+    currency: String,
+    #[serde(with = "rust_decimal::serde::str")]
+    available: Decimal,
+    #[serde(with = "rust_decimal::serde::str")]
+    pending: Decimal,
+}
+
+/// Relay balance with amounts truncated to whole units. Served to clients predating API version 2.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BalanceViewV1 {
     currency: String,
     available: i64,
     pending: i64,
 }
 
+impl TryFrom<BalanceView> for BalanceViewV1 {
+    type Error = String;
+
+    fn try_from(b: BalanceView) -> Result<Self, Self::Error> {
+        let whole = |d: Decimal| {
+            d.trunc()
+                .to_i64()
+                .ok_or(format!("balance {} does not fit, use API version 2", d))
+        };
+        Ok(BalanceViewV1 {
+            available: whole(b.available)?,
+            pending: whole(b.pending)?,
+            currency: b.currency,
+        })
+    }
+}
+
+/// Validate drafting `delta` on top of the balance `cur`, see `Balances::draft`.
+fn check_draft(cur: &(Decimal, Decimal), delta: Decimal) -> Result<(), String> {
+    if cur.1 != Decimal::ZERO {
+        return Err("balance change already pending!".to_string());
+    }
+
+    if delta.is_sign_negative() {
+        // it's a withdrawal
+        if cur.0 <= Decimal::ZERO {
+            Err("you got zero cash!".to_string())
+        } else if cur.0 + delta <= Decimal::ZERO {
+            Err(format!(
+                "insufficient balance: {} requested, {} available",
+                delta, cur.0
+            ))
+        } else if Decimal::MIN - delta >= cur.0 {
+            // check for underflow
+            Err("balance overflow!!!".to_string())
+        } else {
+            Ok(())
+        }
+    } else {
+        // it's a share reward... probably
+        // just check for overflow
+        if Decimal::MAX - delta <= cur.0 {
+            return Err("balance overflow!!!".to_string());
+        }
+        Ok(())
+    }
+}
+
 /// The balances table with actual and pending relay balances.
 impl Balances {
     /// Draft a pending change. This prevents other changes from being drafted simultaneously and
@@ -128,35 +171,29 @@ impl Balances {
     pub async fn draft(&mut self, rk: &str, delta: Decimal) -> Result<(), String> {
         // TODO look into not allocating string copy
         let mut cur = self.h.entry(rk.to_string()).or_default().write().await;
+        check_draft(&cur, delta)?;
+        // to be finalized later
+        cur.1 = delta;
+        Ok(())
+    }
 
-        if cur.1 != Decimal::ZERO {
-            return Err("balance change already pending!".to_string());
-        }
-
-        if delta.is_sign_negative() {
-            // it's a withdrawal
-            if cur.0 <= Decimal::ZERO {
-                return Err("you got zero cash!".to_string());
-            } else if cur.0 + delta <= Decimal::ZERO {
-                return Err(format!(
-                    "insufficient balance: {} requested, {} available",
-                    delta, cur.0
-                ));
-            } else if Decimal::MIN - delta >= cur.0 {
-                // check for underflow
-                return Err("balance overflow!!!".to_string());
-            } else {
-                // to be finalized later
-                cur.1 = delta;
-            }
-        } else {
-            // it's a share reward... probably
-            // just check for overflow
-            if Decimal::MAX - delta <= cur.0 {
-                return Err("balance overflow!!!".to_string());
-            }
-            cur.1 = delta;
-        };
+    /// Check whether a pending change could be drafted right now, without drafting it.
+    pub async fn can_draft(&self, rk: &str, delta: Decimal) -> Result<(), String> {
+        match self.h.get(rk) {
+            Some(b) => check_draft(&*b.read().await, delta),
+            None => check_draft(&Default::default(), delta),
+        }
+    }
+
+    /// Credit a share reward to the available balance right away. Rewards need no confirmation,
+    /// so unlike withdrawals they do not go through the pending change.
+    pub async fn credit(&mut self, rk: &str, delta: Decimal) -> Result<(), String> {
+        // TODO look into not allocating string copy
+        let mut cur = self.h.entry(rk.to_string()).or_default().write().await;
+        cur.0 = cur
+            .0
+            .checked_add(delta)
+            .ok_or("balance overflow!!!".to_string())?;
         Ok(())
     }
 
@@ -192,13 +229,37 @@ impl Balances {
         }
     }
 
-    /// Get the current balance for a relay.
-    pub async fn get(&self, rk: &str) -> Option<BalanceView> {
+    /// Is there a pending change for a relay?
+    pub async fn drafted(&self, rk: &str) -> bool {
+        match self.h.get(rk) {
+            Some(b) => b.read().await.1 != Decimal::ZERO,
+            None => false,
+        }
+    }
+
+    /// The relays with a pending change.
+    pub async fn drafts(&self) -> Vec<String> {
+        let mut v = Vec::new();
+        for (k, b) in &self.h {
+            if b.read().await.1 != Decimal::ZERO {
+                v.push(k.to_owned());
+            }
+        }
+        v
+    }
+
+    /// Get the current available balance for a relay.
+    pub async fn available(&self, rk: &str) -> Option<Decimal> {
+        Some(self.h.get(rk)?.read().await.0)
+    }
+
+    /// Get the current balance for a relay, in the configured servicekey `currency`.
+    pub async fn get(&self, rk: &str, currency: &str) -> Option<BalanceView> {
         let bal = self.h.get(rk)?.read().await;
         Some(BalanceView {
-            currency: "USD".to_string(), // TODO
-            available: bal.0.to_i64()?,
-            pending: bal.1.to_i64()?,
+            currency: currency.to_string(),
+            available: bal.0,
+            pending: bal.1,
         })
     }
 
@@ -216,78 +277,535 @@ impl Balances {
         Self { h }
     }
 
-    pub fn export(&self) -> HashMap<String, (Decimal, Decimal)> {
+    pub async fn export(&self) -> HashMap<String, (Decimal, Decimal)> {
         let mut h = HashMap::new();
         h.reserve(self.h.len());
         for (k, v) in &self.h {
-            h.insert(k.to_owned(), *v.blocking_read());
+            h.insert(k.to_owned(), *v.read().await);
         }
         h
     }
 }
 
+/// The spent pofs registry prevents a pof from being used for more than one activation.
+#[derive(Clone, Debug, Default)]
+pub struct SpentPofs {
+    /// Pof type and nonce mapped to the pof expiration time.
+    h: HashMap<String, i64>,
+}
+
+impl SpentPofs {
+    /// The registry key of a pof.
+    pub fn key(pof: &Pof) -> String {
+        vec![pof.pof_type.clone(), pof.nonce.clone()].join(":")
+    }
+
+    /// Has the pof with this key been spent already?
+    pub fn contains(&self, key: &str) -> bool {
+        self.h.contains_key(key)
+    }
+
+    /// Mark the pof with this key and expiration time as spent. Returns false if it was already
+    /// spent.
+    pub fn spend(&mut self, key: String, expiration: i64) -> bool {
+        match self.h.entry(key) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(e) => {
+                e.insert(expiration);
+                true
+            }
+        }
+    }
+
+    /// Forget pofs which have expired by `t`, as they cannot be used again anyway.
+    /// Returns the number of entries removed.
+    pub fn gc(&mut self, t: i64) -> usize {
+        let n = self.h.len();
+        self.h.retain(|_, exp| *exp > t);
+        n - self.h.len()
+    }
+
+    pub fn from(src: HashMap<String, i64>) -> Self {
+        Self { h: src }
+    }
+
+    pub fn export(&self) -> HashMap<String, i64> {
+        self.h.clone()
+    }
+}
+
+/// The keyed withdrawals registry allows a withdrawal request to be retried safely by answering
+/// retries with the original withdrawal.
+#[derive(Clone, Debug, Default)]
+pub struct KeyedWithdrawals {
+    /// Relay public key and idempotency key mapped to the request time and the resulting
+    /// withdrawal, which is unknown while the request is in progress.
+    h: HashMap<String, (i64, Option<Withdrawal>)>,
+}
+
+impl KeyedWithdrawals {
+    /// The registry key of a relay's idempotency key.
+    pub fn key(rk: &str, key: &str) -> String {
+        vec![rk, key].join(":")
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Option<Withdrawal>> {
+        self.h.get(key).map(|(_, w)| w)
+    }
+
+    pub fn insert(&mut self, key: String, t: i64, w: Option<Withdrawal>) {
+        self.h.insert(key, (t, w));
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.h.remove(key);
+    }
+
+    /// The relay public keys and idempotency keys of requests still in progress.
+    pub fn in_progress(&self) -> Vec<(String, String)> {
+        self.h
+            .iter()
+            .filter(|(_, (_, w))| w.is_none())
+            // relay public keys are base64, so the first separator ends them
+            .filter_map(|(k, _)| k.split_once(':'))
+            .map(|(rk, key)| (rk.to_string(), key.to_string()))
+            .collect()
+    }
+
+    /// Forget withdrawals requested before `t`. Returns the number of entries removed.
+    pub fn gc(&mut self, t: i64) -> usize {
+        let n = self.h.len();
+        self.h.retain(|_, (req, _)| *req >= t);
+        n - self.h.len()
+    }
+
+    pub fn from(src: HashMap<String, (i64, Option<Withdrawal>)>) -> Self {
+        Self { h: src }
+    }
+
+    pub fn export(&self) -> HashMap<String, (i64, Option<Withdrawal>)> {
+        self.h.clone()
+    }
+}
+
+// only read to migrate state saved before the journal was introduced
 const BALANCES_FILE: &'static str = &"balances.json";
 
+/// Compact the journal into a snapshot after this many records.
+const SNAPSHOT_EVERY: usize = 1000;
+
 /// The tracker keeps track of: Sharetokens, shares (only during settlement), resulting balances.
 impl Tracker {
     /// Creates a new tracker with the given share reward calculation function and settlement check
-    /// interval.
+    /// interval. Keyed withdrawals are remembered for `key_retention`, settlement receipts are
+    /// signed with `key`.
     pub async fn new(
         root_path: PathBuf,
         calc: SafeCalc,
         interval: i64,
         txn_chan: Receiver<BalanceUpdate>,
+        log_cfg: LogCfg,
+        key_retention: Duration,
+        key: SigningKey,
     ) -> Result<Tracker, io::Error> {
-        use tokio::fs::{create_dir_all, read};
+        use tokio::fs::{create_dir_all, read, remove_dir_all, remove_file};
 
         // create state dirs
         let archive_path = root_path.join("archive");
         let unsettled_path = root_path.join("unsettled");
+        let quarantine_path = root_path.join("quarantine");
         create_dir_all(&archive_path).await?;
         create_dir_all(&unsettled_path).await?;
 
-        let saved: Result<_, Box<dyn Error>> = async {
-            serde_json::from_slice(&read(root_path.join(BALANCES_FILE)).await?)
-                .map_err(|e| e.into())
+        let (journal, snapshot, records) = Journal::open(&root_path).await?;
+        let log = EventLog::open(root_path.join("log"), log_cfg).await?;
+        let receipts = Receipts::open(root_path.join("receipts")).await?;
+
+        let snapshot = match snapshot {
+            Some(s) => s,
+            None => {
+                let saved: Result<_, Box<dyn Error>> = async {
+                    serde_json::from_slice(&read(root_path.join(BALANCES_FILE)).await?)
+                        .map_err(|e| e.into())
+                }
+                .await;
+                Snapshot {
+                    balances: saved.unwrap_or_default(),
+                    ..Default::default()
+                }
+            }
+        };
+
+        let mut balances = Balances::from(snapshot.balances);
+        let mut spent = SpentPofs::from(snapshot.spent);
+        let mut keyed = KeyedWithdrawals::from(snapshot.keyed);
+        let mut finished = snapshot.finished;
+        let key_retention = key_retention.as_secs() as i64;
+        let mut archive_q = snapshot.archive_q;
+        let mut receipts_q = snapshot.receipts_q;
+
+        // replay the journal on top of the snapshot
+        let mut queue: HashMap<String, Sharetoken> = snapshot
+            .queue
+            .into_iter()
+            .map(|st| (st.filename(), st))
+            .collect();
+        for rec in records {
+            match rec {
+                Record::Push(st) => {
+                    queue.insert(st.filename(), st);
+                }
+                Record::Settle(names, receipts) => {
+                    for name in names {
+                        if let Some(st) = queue.remove(&name) {
+                            archive_q.push(st)
+                        }
+                    }
+                    for rcpt in &receipts {
+                        let _ = balances.credit(&rcpt.relay, rcpt.reward).await;
+                    }
+                    // they may have been written already, readers skip duplicates
+                    receipts_q.extend(receipts);
+                }
+                Record::Draft(rk, delta) => balances
+                    .draft(&rk, delta)
+                    .await
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+                Record::Commit(rk, act) => balances.commit(&rk, act).await,
+                Record::Final(id, rk, act) => {
+                    // skipped the same way it was when journaled
+                    if balances.drafted(&rk).await {
+                        balances.commit(&rk, act).await
+                    }
+                    finished.insert(id, utimenow());
+                }
+                Record::Spend(key, exp) => {
+                    spent.spend(key, exp);
+                }
+                Record::Keyed(key, t, w) => keyed.insert(key, t, w),
+                Record::Unkeyed(key) => keyed.remove(&key),
+            }
         }
-        .await;
+        spent.gc(utimenow());
+        keyed.gc(utimenow() - key_retention);
+        finished.retain(|_, t| *t >= utimenow() - key_retention);
+        // only queued sharetokens are indexed, settled ones are past settlement close and rejected
+        // by validation already
+        let mut seen: HashMap<String, i64> = queue
+            .iter()
+            .map(|(name, st)| (name.clone(), st.contract.settlement_close))
+            .collect();
+        let mut sts: BinaryHeap<_> = queue.into_values().map(ChronoSort).collect();
 
-        let balances = Balances::from(saved.unwrap_or_default());
+        // reload sharetokens left unsettled by versions saving them on shutdown
+        let (st_paths, stray) = st_files(&unsettled_path).await?;
+        let mut reloaded = Vec::new();
+        let mut bad = stray.len();
+        for p in st_paths {
+            let loaded: Result<Signed<Sharetoken>, Box<dyn Error>> =
+                async { serde_json::from_slice(&read(&p).await?).map_err(|e| e.into()) }.await;
+            // stringify the error right away, Box<dyn Error> must not be held across awaits
+            match loaded.map_err(|e| e.to_string()) {
+                Ok(st) => {
+                    if seen
+                        .insert(st.filename(), st.contract.settlement_close)
+                        .is_none()
+                    {
+                        sts.push(ChronoSort(st.0));
+                    }
+                    // deleted once it is contained in the snapshot
+                    reloaded.push(p);
+                }
+                Err(e) => {
+                    warn!(
+                        "Invalid unsettled sharetoken {}: {}!",
+                        p.to_string_lossy(),
+                        e
+                    );
+                    quarantine(&p, &unsettled_path, &quarantine_path).await?;
+                    bad += 1;
+                }
+            }
+        }
+        for p in &stray {
+            warn!("Stray file {} in unsettled dir!", p.to_string_lossy());
+            quarantine(p, &unsettled_path, &quarantine_path).await?;
+        }
+        if reloaded.len() > 0 || bad > 0 {
+            debug!(
+                "{} queued sharetokens restored, {} quarantined.",
+                reloaded.len(),
+                bad
+            );
+        }
 
-        Ok(Tracker {
-            root_path,
+        let mut tracker = Tracker {
             archive_path,
-            unsettled_path,
             calc,
             interval,
-            sts: BinaryHeap::new(),
+            sts,
+            seen,
             balances,
-            archive_q: Vec::new(),
+            spent,
+            keyed,
+            key_retention,
+            finished,
+            archive_q,
             totals: HashMap::new(),
             tokens: HashMap::new(),
             txn_chan,
-            log: TrackerLog::new(),
-        })
+            log,
+            journal,
+            receipts,
+            receipts_q,
+            key,
+        };
+        // start off a clean journal
+        tracker.snapshot().await?;
+        // the reloaded sharetokens are part of the snapshot now
+        for p in reloaded {
+            remove_file(&p).await?;
+        }
+        // all files have been deleted or quarantined, so only empty subdirs are left
+        remove_dir_all(&unsettled_path).await?;
+        create_dir_all(&unsettled_path).await?;
+        Ok(tracker)
+    }
+
+    /// Writes a snapshot of the entire durable state and compacts the journal.
+    pub async fn snapshot(&mut self) -> Result<(), io::Error> {
+        let s = Snapshot {
+            seq: 0, // filled in by the journal
+            balances: self.balances.export().await,
+            spent: self.spent.export(),
+            keyed: self.keyed.export(),
+            finished: self.finished.clone(),
+            queue: self.sts.iter().map(|st| st.0.clone()).collect(),
+            archive_q: self.archive_q.clone(),
+            receipts_q: self.receipts_q.clone(),
+        };
+        self.journal.compact(s).await
+    }
+
+    /// Writes all in-memory state to disk. Intended to be called once on shutdown, after a final
+    /// `tick`.
+    pub async fn flush(&mut self) -> Result<(), io::Error> {
+        self.snapshot().await?;
+        self.log.save().await
+    }
+
+    /// Draft a journaled pending balance change, see `Balances::draft`. If the change is for a
+    /// withdrawal requested with the idempotency `key`, the request is durably recorded as in
+    /// progress along with it, see `set_keyed`.
+    pub async fn draft(
+        &mut self,
+        rk: &str,
+        delta: Decimal,
+        key: Option<&str>,
+    ) -> Result<(), String> {
+        // only drafts that take effect are journaled
+        self.balances.can_draft(rk, delta).await?;
+        let keyed = key.map(|key| (KeyedWithdrawals::key(rk, key), utimenow()));
+        let mut rs = vec![Record::Draft(rk.to_string(), delta)];
+        if let Some((key, t)) = &keyed {
+            rs.push(Record::Keyed(key.clone(), *t, None));
+        }
+        self.journal
+            .append_all(&rs)
+            .await
+            .map_err(|e| format!("could not write tracker journal: {}", e))?;
+        self.balances.draft(rk, delta).await?;
+        if let Some((key, t)) = keyed {
+            self.keyed.insert(key, t, None);
+        }
+        self.log
+            .add(Event::WithdrawalPending(rk.to_string(), delta));
+        Ok(())
+    }
+
+    /// The relays with a pending balance change, see `Balances::drafts`.
+    pub async fn drafts(&self) -> Vec<String> {
+        self.balances.drafts().await
+    }
+
+    /// Apply or abort a journaled pending balance change, see `Balances::commit`.
+    pub async fn commit(&mut self, rk: &str, act: Action) -> Result<(), io::Error> {
+        self.journal
+            .append(&Record::Commit(rk.to_string(), act))
+            .await?;
+        self.balances.commit(rk, act).await;
+        self.log.add(Event::WithdrawalFinal(rk.to_string(), act));
+        Ok(())
+    }
+
+    /// Finalize the pending balance change of relay `rk` made for withdrawal `id`, see
+    /// `Balances::commit`. Returns false without changing anything if the withdrawal has been
+    /// finalized before, so repeated balance updates are harmless.
+    pub async fn finish(&mut self, id: &str, rk: &str, act: Action) -> Result<bool, io::Error> {
+        if self.finished.contains_key(id) {
+            return Ok(false);
+        }
+        self.journal
+            .append(&Record::Final(id.to_string(), rk.to_string(), act))
+            .await?;
+        if self.balances.drafted(rk).await {
+            self.balances.commit(rk, act).await;
+            self.log.add(Event::WithdrawalFinal(rk.to_string(), act));
+        } else {
+            warn!(
+                "Withdrawal {} of {} finalized without a pending balance change!",
+                id, rk
+            );
+        }
+        self.finished.insert(id.to_string(), utimenow());
+        Ok(true)
+    }
+
+    /// Has the balance change made for withdrawal `id` been finalized?
+    pub fn finished(&self, id: &str) -> bool {
+        self.finished.contains_key(id)
+    }
+
+    /// Look up a withdrawal requested by relay `rk` with an idempotency key. Returns `Some(None)`
+    /// while the original request is still in progress.
+    pub fn keyed(&self, rk: &str, key: &str) -> Option<&Option<Withdrawal>> {
+        self.keyed.get(&KeyedWithdrawals::key(rk, key))
+    }
+
+    /// The relay public keys and idempotency keys of withdrawal requests still in progress, see
+    /// `keyed`.
+    pub fn keys_in_progress(&self) -> Vec<(String, String)> {
+        self.keyed.in_progress()
+    }
+
+    /// Durably record the withdrawal requested by relay `rk` with an idempotency key, or that it
+    /// is in progress if `w` is `None`.
+    pub async fn set_keyed(
+        &mut self,
+        rk: &str,
+        key: &str,
+        w: Option<Withdrawal>,
+    ) -> Result<(), io::Error> {
+        let key = KeyedWithdrawals::key(rk, key);
+        let t = utimenow();
+        self.journal
+            .append(&Record::Keyed(key.clone(), t, w.clone()))
+            .await?;
+        self.keyed.insert(key, t, w);
+        Ok(())
+    }
+
+    /// Durably forget an idempotency key of relay `rk` whose request failed, so it can be retried.
+    pub async fn unset_keyed(&mut self, rk: &str, key: &str) -> Result<(), io::Error> {
+        let key = KeyedWithdrawals::key(rk, key);
+        self.journal.append(&Record::Unkeyed(key.clone())).await?;
+        self.keyed.remove(&key);
+        Ok(())
+    }
+
+    /// Saves the event log, returning its end for use with `EventReader::until`. Events read up to
+    /// it agree with the tracker state until it is next changed.
+    pub async fn log_end(&mut self) -> Result<LogEnd, io::Error> {
+        self.log.sync().await
+    }
+
+    /// Path to the event log folder, for use with `EventReader`.
+    pub fn log_dir(&self) -> PathBuf {
+        self.log.dir().to_path_buf()
+    }
+
+    /// Path to the receipts folder, for use with `receipts::read`.
+    pub fn receipts_dir(&self) -> PathBuf {
+        self.receipts.dir().to_path_buf()
+    }
+
+    /// Mark a pof as spent. Returns false if it was already spent.
+    pub async fn spend(&mut self, pof: &Pof) -> Result<bool, io::Error> {
+        let key = SpentPofs::key(pof);
+        if self.spent.contains(&key) {
+            return Ok(false);
+        }
+        self.journal
+            .append(&Record::Spend(key.clone(), pof.expiration))
+            .await?;
+        Ok(self.spent.spend(key, pof.expiration))
     }
 
     /// Enqueues a `Sharetoken` for settlement. The `Sharetoken` itself contains all the necessary
     /// information to perform the settlement in favor of a relay for a given servicekey.
-    pub fn push(&mut self, st: Sharetoken) {
-        self.log.add(Event::Submission(
-            st.public_key.to_string(),
-            st.relay_pubkey.to_string(),
-        ));
-        self.sts.push(ChronoSort(st));
+    /// Returns false without enqueueing if the `Sharetoken` has been seen before.
+    pub async fn push(&mut self, st: Sharetoken) -> Result<bool, io::Error> {
+        Ok(self.push_batch(vec![st]).await?[0])
+    }
+
+    /// Enqueue `Sharetoken`s like `push`, journaling all of them with a single sync. Returns
+    /// whether each one was enqueued, in order. Fails without enqueueing any of them if they
+    /// cannot be journaled.
+    pub async fn push_batch(&mut self, sts: Vec<Sharetoken>) -> Result<Vec<bool>, io::Error> {
+        // duplicates within the batch count as seen too
+        let mut batch = HashSet::new();
+        let fresh: Vec<bool> = sts
+            .iter()
+            .map(|st| !self.seen.contains_key(&st.filename()) && batch.insert(st.filename()))
+            .collect();
+        let records: Vec<Record> = sts
+            .iter()
+            .zip(&fresh)
+            .filter(|(_, fresh)| **fresh)
+            .map(|(st, _)| Record::Push(st.clone()))
+            .collect();
+        self.journal.append_all(&records).await?;
+        for (st, _) in sts.into_iter().zip(&fresh).filter(|(_, fresh)| **fresh) {
+            self.seen
+                .insert(st.filename(), st.contract.settlement_close);
+            self.log.add(Event::Submission(
+                st.public_key.to_string(),
+                st.relay_pubkey.to_string(),
+            ));
+            self.sts.push(ChronoSort(st));
+        }
+        Ok(fresh)
     }
 
     /// Synchronous (blocking) tracker tick to settle (over)due Sharetokens.
     /// Intended to be called periodically from a separate Tokio task.
     /// Returns the next possible time for checking: either the settlement close of the next
     /// `Sharetoken` in the queue or `interval` seconds later if the queue is empty.
-    pub async fn tick(&mut self, t: i64) -> i64 {
+    /// Fails without settling anything if the settlement cannot be journaled.
+    pub async fn tick(&mut self, t: i64) -> Result<i64, io::Error> {
         debug!("Tracker tick!");
         let mut next = t + self.interval;
 
+        let n = self.spent.gc(t);
+        if n > 0 {
+            debug!("{} expired pofs removed from spent registry.", n);
+        }
+        let n = self.keyed.gc(t - self.key_retention);
+        if n > 0 {
+            debug!(
+                "{} expired idempotency keys removed from keyed withdrawals.",
+                n
+            );
+        }
+        let n = self.finished.len();
+        self.finished
+            .retain(|_, fin| *fin >= t - self.key_retention);
+        if n > self.finished.len() {
+            debug!(
+                "{} expired withdrawal ids removed from finished withdrawals.",
+                n - self.finished.len()
+            );
+        }
+        let n = self.seen.len();
+        self.seen.retain(|_, close| *close > t);
+        if n > self.seen.len() {
+            debug!(
+                "{} sharetokens past settlement close removed from seen index.",
+                n - self.seen.len()
+            );
+        }
+
+        let mut due = Vec::new();
         loop {
             if let Some(st) = self.sts.peek() {
                 debug!("Peeked ST from queue.");
@@ -303,7 +821,7 @@ impl Tracker {
                         let sks = Base64(st.public_key()).to_string();
                         *self.totals.entry(sks.clone()).or_default() += Decimal::ONE;
                         *self.tokens.entry((sks, pks)).or_default() += Decimal::ONE;
-                        self.archive_q.push(st.0);
+                        due.push(st.0);
                         // look for more tokens to settle
                         continue;
                     }
@@ -321,16 +839,67 @@ impl Tracker {
         }
 
         // calculate actual balances off shares
-        for (v, sk, rk) in self
+        let mut receipts = Vec::new();
+        for (n, total, sk, rk) in self
             .tokens
             .drain()
-            .map(|((sk, rk), v)| (v / self.totals[&sk], sk, rk))
+            .map(|((sk, rk), n)| (n, self.totals[&sk], sk, rk))
         {
-            let r = self.calc.reward(v);
-            assert!(r.is_sign_positive()); // the subsequent unwrap depends on this
-            self.balances.draft(&rk, r).await.unwrap();
-            self.balances.commit(&rk, Action::Apply).await;
-            self.log.add(Event::Distribution(sk, rk, r))
+            let r = self.calc.reward(n / total);
+            assert!(r.is_sign_positive());
+            let mut rcpt = Receipt {
+                public_key: Base64(self.key.verifying_key()),
+                signature: Base64([0; 64]),
+                servicekey: sk,
+                relay: rk,
+                tokens: n,
+                total,
+                reward: r,
+                settled_at: t,
+            };
+            rcpt.signature = Base64(self.key.sign(rcpt.message().as_bytes()).to_bytes());
+            receipts.push(rcpt);
+        }
+
+        // the popped tokens and their distributions are journaled as one, so a settlement is
+        // either replayed entirely or not at all
+        if due.len() > 0 {
+            let r = Record::Settle(
+                due.iter().map(|st| st.filename()).collect(),
+                receipts.clone(),
+            );
+            if let Err(e) = self.journal.append(&r).await {
+                // nothing is settled yet, so the tokens go back to be settled on the next tick
+                self.sts.extend(due.into_iter().map(ChronoSort));
+                self.totals.clear();
+                return Err(e);
+            }
+            self.archive_q.extend(due);
+        }
+        for rcpt in &receipts {
+            if let Err(e) = self.balances.credit(&rcpt.relay, rcpt.reward).await {
+                warn!(
+                    "Could not credit reward of {} to {}: {}",
+                    rcpt.reward, rcpt.relay, e
+                );
+            }
+            self.log.add(Event::Distribution(
+                rcpt.servicekey.clone(),
+                rcpt.relay.clone(),
+                rcpt.reward,
+            ))
+        }
+        self.receipts_q.extend(receipts);
+        if self.receipts_q.len() > 0 {
+            // journaled already, so on failure they are just retried on the next tick
+            match self.receipts.append(&self.receipts_q).await {
+                Ok(()) => self.receipts_q.clear(),
+                Err(e) => warn!(
+                    "Could not write {} settlement receipts: {}",
+                    self.receipts_q.len(),
+                    e
+                ),
+            }
         }
         if self.balances.len() > 0 {
             debug!("* ST balances = {:#?}", self.balances);
@@ -353,21 +922,29 @@ impl Tracker {
             );
             self.archive_q.clear()
         }
+        if self.journal.records() >= SNAPSHOT_EVERY {
+            if let Err(e) = self.snapshot().await {
+                warn!("Could not write tracker snapshot: {}", e);
+            }
+        }
         debug!("Tracker tick finished.");
-        if let Err(e) = self.log.save(&self.root_path).await {
+        if let Err(e) = self.log.save().await {
             debug!("Could not write tracker log: {}", e.to_string());
         };
-        next
+        Ok(next)
     }
 
     pub async fn txn_tick(&mut self) {
-        debug!("Looking for balance update...");
-        if let Some(upd) = self.txn_chan.try_recv().ok() {
+        debug!("Looking for balance updates...");
+        while let Some(upd) = self.txn_chan.try_recv().ok() {
             debug!("Balance update received! {:?}", upd);
-            self.balances.commit(&upd.relay, upd.action).await;
-            self.log.add(Event::WithdrawalFinal(upd.relay, upd.action))
-        } else {
-            debug!("No balance update received!");
+            // the watcher sends the update again until the withdrawal is finished
+            if let Err(e) = self.finish(&upd.id, &upd.relay, upd.action).await {
+                warn!(
+                    "Could not write tracker journal, balance update postponed: {}",
+                    e
+                );
+            }
         }
     }
 
@@ -408,16 +985,51 @@ impl Tracker {
     // TODO merge the 2 implementations somehow?
 }
 
-// on graceful shutdown, write everything to disk
-impl Drop for Tracker {
-    fn drop(&mut self) {
-        let n = self.sts.len();
-        if n > 0 {
-            for st in &self.sts {
-                self.save_st_sync(&st.0, &self.unsettled_path)
+/// List the paths of all `Sharetoken`s saved under `dir` as `<servicekey>/<relay>/<filename>`,
+/// along with any other files found above that depth.
+async fn st_files(dir: &Path) -> Result<(Vec<PathBuf>, Vec<PathBuf>), io::Error> {
+    use tokio::fs::read_dir;
+    let (mut paths, mut stray) = (Vec::new(), Vec::new());
+    let mut sks = read_dir(dir).await?;
+    while let Some(sk) = sks.next_entry().await? {
+        if !sk.file_type().await?.is_dir() {
+            stray.push(sk.path());
+            continue;
+        }
+        let mut rks = read_dir(sk.path()).await?;
+        while let Some(rk) = rks.next_entry().await? {
+            if !rk.file_type().await?.is_dir() {
+                stray.push(rk.path());
+                continue;
+            }
+            let mut sts = read_dir(rk.path()).await?;
+            while let Some(st) = sts.next_entry().await? {
+                paths.push(st.path());
             }
-            debug!("{} yet unsettled sharetokens written to unsettled dir.", n);
         }
+    }
+    Ok((paths, stray))
+}
+
+/// Moves `p` from under `dir` to the same place under `quarantine_dir`.
+async fn quarantine(p: &Path, dir: &Path, quarantine_dir: &Path) -> Result<(), io::Error> {
+    // unwrap: only called for paths under dir
+    let dst = quarantine_dir.join(p.strip_prefix(dir).unwrap());
+    warn!(
+        "Moving {} to {}.",
+        p.to_string_lossy(),
+        dst.to_string_lossy()
+    );
+    if let Some(parent) = dst.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::rename(p, &dst).await
+}
+
+// on graceful shutdown, write what is not journaled to disk
+// queue, balances and spent pofs are all recovered from the journal on startup
+impl Drop for Tracker {
+    fn drop(&mut self) {
         let n = self.archive_q.len();
         if n > 0 {
             for st in &self.archive_q {
@@ -425,10 +1037,5 @@ impl Drop for Tracker {
             }
             debug!("{} already settled sharetokens written to archive dir.", n);
         }
-        let balances = self.balances.export();
-        let _ = std::fs::write(
-            self.root_path.join(BALANCES_FILE),
-            serde_json::to_string(&balances).unwrap(),
-        );
     }
 }
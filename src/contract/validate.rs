@@ -0,0 +1,189 @@
+use crate::api::{PofSource, Sharetoken};
+use ed25519_dalek::{Verifier, VerifyingKey};
+use std::{fmt, time::Duration};
+use ws_common::{
+    api::{Pof, Status},
+    b64e::Base64,
+};
+
+/// Reasons for rejecting a proof of funding.
+#[derive(Debug)]
+pub enum PofError {
+    /// No configured pof source issues pofs of this type.
+    UnknownType(String),
+    /// The pof signature does not verify against the source public key.
+    InvalidSignature,
+    /// The pof expired at the contained time.
+    Expired(i64),
+    /// The pof has already been used for an activation.
+    Spent,
+}
+
+impl PofError {
+    pub fn code(&self) -> u16 {
+        use PofError::*;
+        match self {
+            UnknownType(_) => 400,
+            InvalidSignature => 401,
+            Expired(_) => 403,
+            Spent => 409,
+        }
+    }
+}
+
+impl fmt::Display for PofError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use PofError::*;
+        match self {
+            UnknownType(t) => write!(f, "unknown pof type: {}", t),
+            InvalidSignature => write!(f, "invalid pof signature"),
+            Expired(t) => write!(f, "pof expired at {}", t),
+            Spent => write!(f, "pof already spent"),
+        }
+    }
+}
+
+impl From<PofError> for Status {
+    fn from(e: PofError) -> Self {
+        Status {
+            code: e.code(),
+            desc: e.to_string(),
+        }
+    }
+}
+
+/// The message signed by a pof source, see `auth::mk_pof`.
+fn pof_msg(pof: &Pof) -> String {
+    vec![
+        pof.pof_type.clone(),
+        pof.expiration.to_string(),
+        pof.nonce.clone(),
+    ]
+    .join(":")
+}
+
+/// Check that a pof was issued by one of the configured sources and is still valid at `now`.
+pub fn pof(pof: &Pof, sources: &[PofSource], now: i64) -> Result<(), PofError> {
+    let src = sources
+        .iter()
+        .find(|s| s.pof_type == pof.pof_type)
+        .ok_or_else(|| PofError::UnknownType(pof.pof_type.clone()))?;
+
+    src.pubkey
+        .0
+        .verify(pof_msg(pof).as_bytes(), &pof.signature.0.into())
+        .map_err(|_| PofError::InvalidSignature)?;
+
+    if pof.expiration <= now {
+        return Err(PofError::Expired(pof.expiration));
+    }
+
+    Ok(())
+}
+
+/// Reasons for rejecting a submitted sharetoken.
+#[derive(Debug)]
+pub enum SharetokenError {
+    /// The sharetoken signature does not verify against its servicekey.
+    InvalidSignature,
+    /// The embedded contract was issued by a different contract.
+    WrongContract,
+    /// The embedded contract signature does not verify against our public key.
+    InvalidContractSignature,
+    /// The sharetoken servicekey is not the one the embedded contract was issued for.
+    ServicekeyMismatch,
+    /// The settlement window closed at the contained time.
+    SettlementClosed(i64),
+    /// The sharetoken timestamp precedes the earliest possible servicekey use.
+    TooEarly(i64),
+    /// The sharetoken timestamp is past settlement close.
+    TooLate(i64),
+    /// The sharetoken has already been submitted.
+    Duplicate,
+}
+
+impl SharetokenError {
+    pub fn code(&self) -> u16 {
+        use SharetokenError::*;
+        match self {
+            InvalidSignature => 401,
+            WrongContract => 400,
+            InvalidContractSignature => 401,
+            ServicekeyMismatch => 403,
+            SettlementClosed(_) => 410,
+            TooEarly(_) | TooLate(_) => 422,
+            Duplicate => 409,
+        }
+    }
+}
+
+impl fmt::Display for SharetokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use SharetokenError::*;
+        match self {
+            InvalidSignature => write!(f, "invalid sharetoken signature"),
+            WrongContract => write!(f, "Sharetoken is not for this contract"),
+            InvalidContractSignature => write!(f, "invalid servicekey contract signature"),
+            ServicekeyMismatch => {
+                write!(
+                    f,
+                    "Sharetoken servicekey was not activated by this contract"
+                )
+            }
+            SettlementClosed(t) => write!(f, "settlement window closed at {}", t),
+            TooEarly(t) => write!(f, "sharetoken timestamp {} precedes servicekey validity", t),
+            TooLate(t) => write!(f, "sharetoken timestamp {} is past settlement close", t),
+            Duplicate => write!(f, "sharetoken already submitted"),
+        }
+    }
+}
+
+impl From<SharetokenError> for Status {
+    fn from(e: SharetokenError) -> Self {
+        Status {
+            code: e.code(),
+            desc: e.to_string(),
+        }
+    }
+}
+
+/// Check that a sharetoken carries a contract issued by us for its servicekey and that it is
+/// submitted within the settlement window. `duration` is the configured servicekey duration.
+pub fn sharetoken(
+    st: &Sharetoken,
+    pk: &Base64<VerifyingKey>,
+    duration: Duration,
+    now: i64,
+) -> Result<(), SharetokenError> {
+    use SharetokenError::*;
+    let c = &st.contract;
+
+    if c.public_key != *pk {
+        return Err(WrongContract);
+    }
+
+    pk.0.verify(c.message().as_bytes(), &c.signature.0.into())
+        .map_err(|_| InvalidContractSignature)?;
+
+    if st.public_key != c.servicekey {
+        return Err(ServicekeyMismatch);
+    }
+
+    if c.settlement_close <= now {
+        return Err(SettlementClosed(c.settlement_close));
+    }
+
+    let earliest = c
+        .settlement_open
+        .saturating_sub(duration.as_secs().try_into().unwrap_or(i64::MAX));
+
+    if st.timestamp < earliest {
+        return Err(TooEarly(st.timestamp));
+    }
+
+    if st.timestamp > c.settlement_close {
+        return Err(TooLate(st.timestamp));
+    }
+
+    Ok(())
+}
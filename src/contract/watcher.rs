@@ -0,0 +1,424 @@
+use super::{
+    payment::{PsError, Registry},
+    tracker::{Action, BalanceUpdate, Tracker},
+};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::{mpsc::Sender, Mutex, RwLock};
+use ws_common::{
+    api::{Withdrawal, WithdrawalRequest, WithdrawalState},
+    time::utimenow,
+};
+
+const PENDING_FILE: &'static str = &"pending_withdrawals.json";
+
+/// A withdrawal awaiting its final state from the payment system.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PendingWithdrawal {
+    /// Payment system withdrawal id, empty until the payment system has confirmed the submission.
+    pub id: String,
+    /// Relay public key.
+    pub relay: String,
+    /// Payout method type of the payment system handling the withdrawal.
+    pub ps_type: String,
+    /// Key the withdrawal was submitted with, so it can be submitted again while unconfirmed.
+    pub key: String,
+    /// The withdrawal request, kept until the payment system has confirmed the submission.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<WithdrawalRequest>,
+    /// Idempotency key the relay requested the withdrawal with, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+    /// The final action once resolved. Kept until the tracker has finalized the balance change,
+    /// so it is not lost if the process stops in between.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action: Option<Action>,
+}
+
+impl PendingWithdrawal {
+    /// A withdrawal of relay `rk` about to be submitted to the payment system of `ps_type`.
+    pub fn new(
+        rk: &str,
+        ps_type: &str,
+        wr: &WithdrawalRequest,
+        idempotency_key: Option<String>,
+    ) -> Self {
+        PendingWithdrawal {
+            id: String::new(),
+            relay: rk.to_string(),
+            ps_type: ps_type.to_string(),
+            key: format!("{:032x}", rand::random::<u128>()),
+            request: Some(wr.clone()),
+            idempotency_key,
+            action: None,
+        }
+    }
+
+    /// Identifies the withdrawal before the payment system has assigned it an id.
+    pub fn reference(&self) -> &str {
+        &self.key
+    }
+
+    /// The balance update finalizing this withdrawal with `act`.
+    pub fn update(&self, act: Action) -> BalanceUpdate {
+        BalanceUpdate {
+            id: self.reference().to_string(),
+            relay: self.relay.clone(),
+            action: act,
+        }
+    }
+}
+
+/// The outcome of a withdrawal state being reported for a withdrawal id.
+#[derive(Debug)]
+pub enum Resolution {
+    /// No such withdrawal was ever pending.
+    Unknown,
+    /// The withdrawal was already resolved with the contained action.
+    AlreadyFinal(Action),
+    /// The withdrawal is still pending.
+    StillPending,
+    /// The withdrawal is now resolved, the contained action must be sent to the tracker.
+    Final(PendingWithdrawal, Action),
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Saved {
+    pending: HashMap<String, PendingWithdrawal>,
+    done: HashMap<String, (i64, Action)>,
+}
+
+/// The set of pending withdrawals, persisted on every change so watching resumes after restart.
+pub struct Pending {
+    /// Path to the pending withdrawals file on disk. (owned)
+    path: PathBuf,
+    /// Withdrawal references mapped to pending and resolved withdrawals, payment system
+    /// withdrawal ids mapped to the time they were resolved and the action they were resolved with.
+    s: Saved,
+    /// How long to remember resolved withdrawal ids, in seconds.
+    retention: i64,
+}
+
+pub type SafePending = Arc<Mutex<Pending>>;
+
+impl Pending {
+    /// Loads the pending withdrawals under `root_path`, remembering resolved ones for `retention`.
+    pub async fn load(root_path: &Path, retention: Duration) -> Result<Self, io::Error> {
+        let path = root_path.join(PENDING_FILE);
+        let s = match tokio::fs::read(&path).await {
+            Ok(b) => serde_json::from_slice(&b)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Saved::default(),
+            Err(e) => return Err(e),
+        };
+        Ok(Pending {
+            path,
+            s,
+            retention: retention.as_secs() as i64,
+        })
+    }
+
+    async fn save(&self) -> Result<(), io::Error> {
+        crate::directory::save(&self.path, &serde_json::to_vec(&self.s)?).await
+    }
+
+    pub async fn insert(&mut self, pw: PendingWithdrawal) -> Result<(), io::Error> {
+        let reference = pw.reference().to_string();
+        self.s.pending.insert(reference.clone(), pw);
+        if let Err(e) = self.save().await {
+            self.s.pending.remove(&reference);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Record the payment system id of the withdrawal `reference` once its submission has been
+    /// confirmed, so it is watched by id from then on.
+    pub async fn submitted(&mut self, reference: &str, id: &str) -> Result<(), io::Error> {
+        let pw = match self.s.pending.get_mut(reference) {
+            Some(pw) => pw,
+            None => return Ok(()),
+        };
+        let old = (pw.id.clone(), pw.request.take());
+        pw.id = id.to_string();
+        if let Err(e) = self.save().await {
+            // unwrap: presence checked above
+            let pw = self.s.pending.get_mut(reference).unwrap();
+            (pw.id, pw.request) = old;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Record a state reported for the withdrawal `id`. Once resolved, a withdrawal does not
+    /// accept further states, so replayed and out-of-order reports are recognized as such.
+    /// A resolution is only final once persisted, otherwise the state must be reported again.
+    pub async fn resolve(
+        &mut self,
+        id: &str,
+        state: &WithdrawalState,
+    ) -> Result<Resolution, io::Error> {
+        let now = utimenow();
+        // expired along with the tracker's record of finished withdrawals
+        self.s.done.retain(|_, (t, _)| *t >= now - self.retention);
+        if let Some((_, act)) = self.s.done.get(id) {
+            return Ok(Resolution::AlreadyFinal(*act));
+        }
+        let reference = match self.get(id) {
+            Some(pw) => pw.reference().to_string(),
+            None => return Ok(Resolution::Unknown),
+        };
+        let act = match action(state) {
+            Some(act) => act,
+            None => return Ok(Resolution::StillPending),
+        };
+        self.s.done.insert(id.to_string(), (now, act));
+        match self.finalize(&reference, act).await {
+            Ok(pw) => Ok(Resolution::Final(pw, act)),
+            Err(e) => {
+                self.s.done.remove(id);
+                Err(e)
+            }
+        }
+    }
+
+    /// Resolve the withdrawal `reference` with `act`, returning it.
+    pub async fn finalize(
+        &mut self,
+        reference: &str,
+        act: Action,
+    ) -> Result<PendingWithdrawal, io::Error> {
+        let pw = self.s.pending.get_mut(reference).ok_or(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no such withdrawal",
+        ))?;
+        pw.action = Some(act);
+        let pw = pw.clone();
+        if let Err(e) = self.save().await {
+            // unwrap: presence checked above
+            self.s.pending.get_mut(reference).unwrap().action = None;
+            return Err(e);
+        }
+        Ok(pw)
+    }
+
+    /// Forget a resolved withdrawal once the tracker has finalized its balance change.
+    pub async fn remove(&mut self, reference: &str) -> Result<(), io::Error> {
+        self.s.pending.remove(reference);
+        self.save().await
+    }
+
+    /// Look up a withdrawal by payment system id.
+    pub fn get(&self, id: &str) -> Option<&PendingWithdrawal> {
+        match id {
+            "" => None,
+            id => self.s.pending.values().find(|pw| pw.id == id),
+        }
+    }
+
+    pub fn all(&self) -> Vec<PendingWithdrawal> {
+        self.s.pending.values().cloned().collect()
+    }
+}
+
+/// The balance action a withdrawal state calls for, if it is final.
+pub fn action(state: &WithdrawalState) -> Option<Action> {
+    match state {
+        WithdrawalState::Pending => None,
+        WithdrawalState::Complete => Some(Action::Apply),
+        _ => Some(Action::Abort),
+    }
+}
+
+/// Have the tracker finalize the resolved withdrawal `pw` right away and forget it, rather than
+/// leaving it to `run`.
+async fn finish(
+    pending: &SafePending,
+    tracker: &RwLock<Tracker>,
+    pw: &PendingWithdrawal,
+    act: Action,
+) -> Result<(), io::Error> {
+    tracker
+        .write()
+        .await
+        .finish(pw.reference(), &pw.relay, act)
+        .await?;
+    pending.lock().await.remove(pw.reference()).await
+}
+
+/// Record `w` as the result of submitting `pw`, finalizing it right away if it is final already.
+/// Whatever is not recorded is picked up by `run` later, as `pw` is submitted again.
+pub async fn confirm(
+    pending: &SafePending,
+    tracker: &RwLock<Tracker>,
+    pw: &PendingWithdrawal,
+    w: &Withdrawal,
+) -> Result<(), io::Error> {
+    // keyed first, so a withdrawal with an id never has its idempotency key left in progress
+    if let Some(key) = &pw.idempotency_key {
+        tracker
+            .write()
+            .await
+            .set_keyed(&pw.relay, key, Some(w.clone()))
+            .await?;
+    }
+    pending
+        .lock()
+        .await
+        .submitted(pw.reference(), &w.id)
+        .await?;
+    let res = pending
+        .lock()
+        .await
+        .resolve(&w.id, &w.state_data.state)
+        .await?;
+    if let Resolution::Final(pw, act) = res {
+        finish(pending, tracker, &pw, act).await?;
+    }
+    Ok(())
+}
+
+/// Abort the withdrawal `pw` whose submission the payment system rejected, so the relay can
+/// request it again.
+pub async fn reject(
+    pending: &SafePending,
+    tracker: &RwLock<Tracker>,
+    pw: &PendingWithdrawal,
+) -> Result<(), io::Error> {
+    pending
+        .lock()
+        .await
+        .finalize(pw.reference(), Action::Abort)
+        .await?;
+    finish(pending, tracker, pw, Action::Abort).await?;
+    if let Some(key) = &pw.idempotency_key {
+        tracker.write().await.unset_keyed(&pw.relay, key).await?;
+    }
+    Ok(())
+}
+
+/// Abort balance changes drafted for withdrawals which were never recorded as pending, as the
+/// process stopped before they could be submitted, and forget idempotency keys left in progress
+/// by them, so the relays can withdraw again. Meant to be run once on startup, before any
+/// withdrawal is requested. Returns the number of balance changes aborted.
+pub async fn reconcile(pending: &Pending, tracker: &mut Tracker) -> Result<usize, io::Error> {
+    let pws = pending.all();
+    let mut n = 0;
+    for rk in tracker.drafts().await {
+        if !pws.iter().any(|pw| pw.relay == rk) {
+            warn!(
+                "Aborting balance change of {} drafted for an unrecorded withdrawal",
+                rk
+            );
+            tracker.commit(&rk, Action::Abort).await?;
+            n += 1;
+        }
+    }
+    for (rk, key) in tracker.keys_in_progress() {
+        let recorded = pws
+            .iter()
+            .any(|pw| pw.relay == rk && pw.idempotency_key.as_ref() == Some(&key));
+        if !recorded {
+            debug!(
+                "Forgetting idempotency key {} of {} left in progress",
+                key, rk
+            );
+            tracker.unset_keyed(&rk, &key).await?;
+        }
+    }
+    Ok(n)
+}
+
+/// Watches pending withdrawals until the payment system reports a final state for them, then
+/// sends the matching `BalanceUpdate` to the tracker until it has been finalized. Withdrawals
+/// whose submission is unconfirmed are submitted again under the same key. All pending
+/// withdrawals are checked every `check_period`.
+pub async fn run(
+    pending: SafePending,
+    payments: Arc<Registry>,
+    tracker: Arc<RwLock<Tracker>>,
+    txn_tx: Sender<BalanceUpdate>,
+    check_period: Duration,
+) {
+    debug!(
+        "- Withdrawal watcher spawned with {} pending withdrawals!",
+        pending.lock().await.all().len()
+    );
+    let mut interval = tokio::time::interval(check_period);
+    loop {
+        interval.tick().await;
+        let pws = pending.lock().await.all();
+        for pw in pws {
+            if let Some(act) = pw.action {
+                // resolved, but possibly not finalized by the tracker yet
+                if tracker.read().await.finished(pw.reference()) {
+                    if let Err(e) = pending.lock().await.remove(pw.reference()).await {
+                        warn!("Could not persist pending withdrawals: {}", e);
+                    }
+                } else if let Err(e) = txn_tx.send(pw.update(act)).await {
+                    warn!("Could not send balance update: {}", e);
+                }
+                continue;
+            }
+            let ps = match payments.get(&pw.ps_type) {
+                Some(ps) => ps,
+                None => {
+                    warn!(
+                        "No payment system for withdrawal {} of type {}!",
+                        pw.reference(),
+                        pw.ps_type
+                    );
+                    continue;
+                }
+            };
+            if pw.id.is_empty() {
+                let wr = match &pw.request {
+                    Some(wr) => wr,
+                    None => continue,
+                };
+                // the payment system deduplicates submissions by key, so this is safe
+                let res = match ps.submit(&pw.key, wr).await {
+                    Ok(w) => confirm(&pending, &tracker, &pw, &w).await,
+                    Err(PsError::Failed(e)) => {
+                        debug!("Withdrawal {} rejected: {}", pw.key, e);
+                        reject(&pending, &tracker, &pw).await
+                    }
+                    Err(PsError::Unknown(e)) => {
+                        debug!("Could not submit withdrawal {} again: {}", pw.key, e);
+                        continue;
+                    }
+                };
+                if let Err(e) = res {
+                    warn!("Could not record withdrawal {}: {}", pw.key, e);
+                }
+                continue;
+            }
+            let id = pw.id;
+            let w = match ps.status(&id).await {
+                Ok(w) => w,
+                Err(e) => {
+                    debug!("Could not check withdrawal {}: {}", id, e);
+                    continue;
+                }
+            };
+            // may have been resolved by a state update in the meantime
+            let res = pending.lock().await.resolve(&id, &w.state_data.state).await;
+            match res {
+                Ok(Resolution::Final(pw, act)) => {
+                    debug!("Withdrawal {} is final: {:?}", id, act);
+                    if let Err(e) = txn_tx.send(pw.update(act)).await {
+                        warn!("Could not send balance update: {}", e);
+                    }
+                }
+                Ok(_) => (),
+                Err(e) => warn!("Could not persist pending withdrawals: {}", e),
+            }
+        }
+    }
+}
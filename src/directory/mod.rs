@@ -1,82 +1,340 @@
-use crate::api::{Public, Relay};
-use axum::{
-    extract::{rejection::JsonRejection, State},
-    http::HeaderMap,
-    response::IntoResponse,
-    Json,
+use crate::{
+    api::{
+        headersignedjson::{HeaderSignedJson, Signatory},
+        Heartbeat, Public, Relay,
+    },
+    state::{Custom, SafeInner},
 };
+use axum::{extract::State, http::HeaderMap, response::IntoResponse, Json};
 use ed25519_dalek::Signer;
-use log::{debug, warn};
-use ws_common::{api::Status, b64e::Base64};
+use log::{debug, error, warn};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{fs::File, io::AsyncWriteExt, sync::Mutex};
+use ws_common::{api::Status, b64e::Base64, state::BaseState, time::utimenow};
+
+const RELAYS_FILE: &'static str = &"relays.json";
+
+/// Changes to the enrolled relays: relay public keys mapped to their new records, or to `None`
+/// for removal.
+pub type Changes = Vec<(String, Option<Relay>)>;
+
+/// The enrolled relays, persisted on every enrollment change so the directory survives restarts.
+#[derive(Clone)]
+pub struct RelayStore {
+    /// Path to the relays file on disk. (owned)
+    path: PathBuf,
+    /// Relay public keys mapped to relay records.
+    pub relays: HashMap<String, Relay>,
+    /// Held while changing the relays, which are saved without holding the state lock, and while
+    /// recording heartbeats, which eviction depends on.
+    writer: Arc<Mutex<()>>,
+}
+
+impl RelayStore {
+    pub async fn load(root_path: &Path) -> Result<Self, io::Error> {
+        let path = root_path.join(RELAYS_FILE);
+        let mut relays: HashMap<String, Relay> = match tokio::fs::read(&path).await {
+            Ok(b) => serde_json::from_slice(&b)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        // heartbeats are not persisted, give restored relays a full ttl to send one
+        let now = utimenow();
+        for r in relays.values_mut() {
+            r.last_seen = Some(now);
+        }
+        Ok(RelayStore {
+            path,
+            relays,
+            writer: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// The relays file contents with `changes` applied.
+    fn staged(&self, changes: &Changes) -> Result<Vec<u8>, io::Error> {
+        let mut relays = self.relays.clone();
+        apply(&mut relays, changes.clone());
+        Ok(serde_json::to_vec(&relays)?)
+    }
+}
+
+fn apply(relays: &mut HashMap<String, Relay>, changes: Changes) {
+    for (pk, r) in changes {
+        match r {
+            Some(r) => relays.insert(pk, r),
+            None => relays.remove(&pk),
+        };
+    }
+}
+
+/// Durably replace the json file at `path` with `b`, so it is either left as it was or replaced
+/// completely.
+pub(crate) async fn save(path: &Path, b: &[u8]) -> Result<(), io::Error> {
+    let tmp = path.with_extension("json.tmp");
+    let mut f = File::create(&tmp).await?;
+    f.write_all(b).await?;
+    f.sync_all().await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+/// Change the enrolled relays as decided by `decide` from the current state, saving the changes
+/// before applying them. Changes are serialized by the writer lock, so the state lock is only held
+/// briefly rather than across the save. Returns the number of relays changed.
+async fn change<F>(st: &BaseState<SafeInner>, decide: F) -> Result<usize, Json<Status>>
+where
+    F: FnOnce(&Custom) -> Result<Changes, Json<Status>>,
+{
+    let writer = st.read().await.directory.writer.clone();
+    let _writer = writer.lock().await;
+    let (changes, path, b) = {
+        let st = st.read().await;
+        let changes = decide(&st)?;
+        let b = st.directory.staged(&changes).map_err(|e| {
+            Json(Status {
+                code: 500,
+                desc: format!("could not serialize relay directory: {}", e),
+            })
+        })?;
+        (changes, st.directory.path.clone(), b)
+    };
+    if changes.is_empty() {
+        return Ok(0);
+    }
+    if let Err(e) = save(&path, &b).await {
+        error!("Could not save relay directory: {}", e);
+        return Err(Json(Status {
+            code: 500,
+            desc: "could not save relay directory".to_string(),
+        }));
+    }
+    let n = changes.len();
+    let mut st = st.write().await;
+    apply(&mut st.directory.relays, changes);
+    recount(&mut st);
+    Ok(n)
+}
+
+/// Check that the relay record is signed by the relay itself.
+fn owner(hsj: &HeaderSignedJson<Relay>) -> Result<(), Json<Status>> {
+    if matches!(hsj.signatory, Signatory::Relay) && hsj.public_key == hsj.data.public_key {
+        Ok(())
+    } else {
+        Err(Json(Status {
+            code: 403,
+            desc: "relay record not signed by the relay".to_string(),
+        }))
+    }
+}
+
+/// Recompute the enrollment counts from the relays map. Returns false if they were off.
+pub fn recount(st: &mut Custom) -> bool {
+    st.public
+        .derived
+        .enrollment
+        .recount(st.directory.relays.values().map(|r| r.role))
+}
 
 pub async fn relays_post_handler(
     State(st): crate::state::Safe,
-    body: Result<Json<Relay>, JsonRejection>,
+    rbody: Result<HeaderSignedJson<Relay>, Json<Status>>,
 ) -> Json<Status> {
-    debug!("Relay POSTed: {:?}", body);
-    match body {
-        Ok(Json(payload)) => {
-            let mut st = st.write().await;
-            if st.public.derived.enrollment.role(payload.role).record(1) {
-                st.relays.insert(payload.address.clone(), payload);
-                Json(Status {
-                    code: 200,
-                    desc: "OK".to_string(),
-                })
-            } else {
-                Json(Status {
-                    code: 500,
-                    desc: "Too many relays!".to_string(),
-                })
-            }
+    let hsj = match rbody {
+        Ok(hsj) => hsj,
+        Err(e) => {
+            debug!("Relay POST body is NOT OK: {:?}", e);
+            return e;
         }
-        Err(e) => Json(Status {
-            code: 400,
-            desc: e.to_string(),
+    };
+    debug!("Relay POSTed: {:?}", hsj.data);
+    if let Err(e) = owner(&hsj) {
+        return e;
+    }
+    let mut payload = hsj.data;
+    payload.last_seen = Some(utimenow());
+    let res = change(&st, |st| {
+        let pk = payload.public_key.to_string();
+        if st
+            .directory
+            .relays
+            .values()
+            .any(|r| r.address == payload.address && r.public_key != payload.public_key)
+        {
+            return Err(Json(Status {
+                code: 403,
+                desc: "address enrolled by another relay".to_string(),
+            }));
+        }
+        if !st
+            .public
+            .defined
+            .private
+            .directory
+            .roles
+            .role(payload.role)
+            .allows(&payload.public_key)
+        {
+            return Err(Json(Status {
+                code: 403,
+                desc: format!("relay not allowed to enroll as {:?}", payload.role),
+            }));
+        }
+        // re-enrolling in the same role takes no additional place
+        let same_role = st
+            .directory
+            .relays
+            .get(&pk)
+            .map_or(false, |r| r.role == payload.role);
+        if !same_role && st.public.derived.enrollment.role(payload.role).full() {
+            return Err(Json(Status {
+                code: 409,
+                desc: format!("no more {:?} relays accepted", payload.role),
+            }));
+        }
+        Ok(vec![(pk, Some(payload))])
+    })
+    .await;
+    match res {
+        Ok(_) => Json(Status {
+            code: 200,
+            desc: "OK".to_string(),
         }),
+        Err(e) => e,
     }
 }
 
 pub async fn relays_delete_handler(
     State(st): crate::state::Safe,
-    body: Result<Json<Relay>, JsonRejection>,
+    rbody: Result<HeaderSignedJson<Relay>, Json<Status>>,
+) -> Json<Status> {
+    let hsj = match rbody {
+        Ok(hsj) => hsj,
+        Err(e) => {
+            debug!("Relay DELETE body is NOT OK: {:?}", e);
+            return e;
+        }
+    };
+    debug!("Relay DELETEd: {:?}", hsj.data);
+    if let Err(e) = owner(&hsj) {
+        return e;
+    }
+    // records are keyed by the relay public key, so a relay can only remove its own
+    let pk = hsj.public_key.to_string();
+    let res = change(&st, |st| match st.directory.relays.contains_key(&pk) {
+        true => Ok(vec![(pk, None)]),
+        false => Err(Json(Status {
+            code: 404,
+            desc: "No such relay".to_string(),
+        })),
+    })
+    .await;
+    match res {
+        Ok(_) => Json(Status {
+            code: 200,
+            desc: "OK".to_string(),
+        }),
+        Err(e) => e,
+    }
+}
+
+pub async fn heartbeat_post_handler(
+    State(st): crate::state::Safe,
+    rbody: Result<HeaderSignedJson<Heartbeat>, Json<Status>>,
 ) -> Json<Status> {
-    debug!("Relay DELETEd: {:?}", body);
-    match body {
-        Ok(Json(payload)) => {
-            let mut st = st.write().await;
-            if !st.relays.contains_key(&payload.address) {
-                return Json(Status {
-                    code: 404,
-                    desc: "No such relay".to_string(),
-                });
-            }
-            let roleinfo = st.public.derived.enrollment.role(payload.role);
-            if !roleinfo.record(-1) {
-                warn!(
-                    "Relay bookkeeping underflow for {:?} at {:?} ({})! Weird.",
-                    payload.role, roleinfo.count, -1,
-                )
-            };
-            st.relays.remove(&payload.address);
+    let hsj = match rbody {
+        Ok(hsj) => hsj,
+        Err(e) => {
+            debug!("Relay heartbeat body is NOT OK: {:?}", e);
+            return e;
+        }
+    };
+    if !matches!(hsj.signatory, Signatory::Relay) {
+        return Json(Status {
+            code: 403,
+            desc: "heartbeat not signed by a relay".to_string(),
+        });
+    }
+    // serialized with changes, so a relay is not evicted based on a heartbeat missed meanwhile
+    let writer = st.read().await.directory.writer.clone();
+    let _writer = writer.lock().await;
+    let now = utimenow();
+    let mut st = st.write().await;
+    // bounds how long a captured heartbeat can be replayed
+    let ttl = st.public.defined.private.directory.relay_ttl.as_secs() as i64;
+    if (hsj.data.timestamp - now).abs() > ttl {
+        return Json(Status {
+            code: 400,
+            desc: format!("heartbeat timestamp {} out of range", hsj.data.timestamp),
+        });
+    }
+    match st.directory.relays.get_mut(&hsj.public_key.to_string()) {
+        Some(r) => {
+            r.last_seen = Some(now);
             Json(Status {
                 code: 200,
                 desc: "OK".to_string(),
             })
         }
-        Err(e) => Json(Status {
-            code: 400,
-            desc: e.to_string(),
+        None => Json(Status {
+            code: 404,
+            desc: "No such relay".to_string(),
         }),
     }
 }
 
+/// Remove relays not seen since `before`, keeping the enrollment counts in step. Returns the
+/// number of relays removed.
+pub async fn evict(st: &BaseState<SafeInner>, before: i64) -> Result<usize, Json<Status>> {
+    change(st, |st| {
+        Ok(st
+            .directory
+            .relays
+            .iter()
+            .filter(|(_, r)| r.last_seen.map_or(true, |t| t < before))
+            .map(|(k, _)| (k.clone(), None))
+            .collect())
+    })
+    .await
+}
+
+/// Periodically evicts relays which have not sent a heartbeat within `ttl`.
+pub async fn run_eviction(st: BaseState<SafeInner>, ttl: Duration) {
+    debug!("- Relay eviction spawned with ttl {:?}!", ttl);
+    let mut interval = tokio::time::interval((ttl / 4).max(Duration::from_secs(1)));
+    loop {
+        interval.tick().await;
+        let before = utimenow() - ttl.as_secs() as i64;
+        // counts are kept in step on every change, so this should never trigger
+        if !recount(&mut *st.write().await) {
+            warn!("Relay enrollment counts were inconsistent, recomputed.");
+        }
+        match evict(&st, before).await {
+            Ok(0) => (),
+            Ok(n) => debug!("{} relays evicted after missing heartbeats.", n),
+            // evicted again next time
+            Err(Json(e)) => warn!("Could not evict relays: {}", e.desc),
+        }
+    }
+}
+
 pub async fn relays_get_handler(State(st): crate::state::Safe) -> impl IntoResponse {
     debug!("Relay GET");
     let mut header_map = HeaderMap::new();
     let k = &st.crypto.key;
     let st = st.read().await;
-    let s = serde_json::to_string(&st.relays.clone()).unwrap();
+    // served by address, records are only keyed by public key internally
+    let relays: HashMap<&str, &Relay> = st
+        .directory
+        .relays
+        .values()
+        .map(|r| (r.address.as_str(), r))
+        .collect();
+    let s = serde_json::to_string(&relays).unwrap();
     let sig = Base64(k.sign(s.as_bytes()).to_bytes()).to_string();
     header_map.insert(
         "wireleap-directory-pubkey",
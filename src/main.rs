@@ -1,6 +1,6 @@
 use crate::{
     api::PubDefined,
-    contract::{calc, tracker},
+    contract::{calc, payment, tracker, watcher},
 };
 use axum::{
     routing::{get, post},
@@ -13,12 +13,11 @@ use rust_decimal_macros::dec;
 use semver::Version;
 use std::error::Error;
 use std::{
-    collections::HashMap,
     env,
     sync::Arc,
     time::{Duration, SystemTime},
 };
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::{mpsc, watch, Mutex, RwLock};
 use tower::layer::Layer;
 use tower_http::normalize_path::NormalizePathLayer;
 use ws_common::{b64e::Base64, bin::common_setup, cfg::ConfigType, time::utime};
@@ -30,6 +29,9 @@ mod contract;
 mod directory;
 mod state;
 
+// seconds to wait before retrying a settlement which could not be journaled
+const SETTLEMENT_RETRY: i64 = 1;
+
 // version of this binary
 static VERSION: Lazy<Version> = Lazy::new(|| Version::parse(env!("CARGO_PKG_VERSION")).unwrap());
 
@@ -41,6 +43,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
     };
 
     let kp = cfg.keypair.clone().unwrap().0;
+    let receipt_key = kp.clone();
+    let drain_timeout = cfg.etc.private.drain_timeout;
+    let log_cfg = cfg.etc.private.log.clone();
+    let key_retention = cfg.etc.private.idempotency_retention;
 
     let calc = calc::DefaultShareCalc {
         value: cfg.etc.servicekey.value,
@@ -49,56 +55,102 @@ async fn main() -> Result<(), Box<dyn Error>> {
     };
 
     let (txn_tx, txn_rx) = mpsc::channel(100);
-    let (watcher_tx, _watcher_rx) = mpsc::channel(100);
+
+    let pending = Arc::new(Mutex::new(
+        watcher::Pending::load(&cfg.root, key_retention).await?,
+    ));
+    let payments = Arc::new(payment::Registry::new(&cfg.etc.payout, &cfg.etc.payouts)?);
 
     let pk = match cfg.keypair {
         Some(Base64(ref kp)) => kp.verifying_key().clone(),
         None => panic!("No keys defined -- is your config.local.json5 in place? `init` done?"),
     };
 
-    let state = ws_common::state::new(
-        kp,
-        Arc::new(RwLock::new(state::Custom {
-            relays: HashMap::new(),
-            public: cfg::mkpublic(cfg.etc.clone(), pk),
-            tracker: Arc::new(RwLock::new(
-                tracker::Tracker::new(cfg.root, Arc::new(Box::new(calc)), 5, txn_rx)
-                    .await
-                    .unwrap(),
-            )),
-            txn_tx: txn_tx.clone(),
-            watcher_tx: watcher_tx.clone(),
-        })),
-    );
+    let mut tracker = tracker::Tracker::new(
+        cfg.root.clone(),
+        Arc::new(Box::new(calc)),
+        5,
+        txn_rx,
+        log_cfg,
+        key_retention,
+        receipt_key,
+    )
+    .await
+    .unwrap();
+    let aborted = watcher::reconcile(&*pending.lock().await, &mut tracker).await?;
+    if aborted > 0 {
+        warn!(
+            "Aborted {} withdrawals interrupted before submission.",
+            aborted
+        );
+    }
+
+    let mut custom = state::Custom {
+        directory: directory::RelayStore::load(&cfg.root).await?,
+        public: cfg::mkpublic(cfg.etc.clone(), pk),
+        tracker: Arc::new(RwLock::new(tracker)),
+        txn_tx: txn_tx.clone(),
+        pending: pending.clone(),
+        payments: payments.clone(),
+    };
+    directory::recount(&mut custom);
+    info!("Restored {} relays.", custom.directory.relays.len());
+
+    let state = ws_common::state::new(kp, Arc::new(RwLock::new(custom)));
+
+    tokio::task::spawn(watcher::run(
+        pending,
+        payments,
+        state.read().await.tracker.clone(),
+        txn_tx,
+        cfg.etc.payout.check_period,
+    ));
+
+    tokio::task::spawn(directory::run_eviction(
+        state.clone(),
+        cfg.etc.private.directory.relay_ttl,
+    ));
 
     let bgstate = state.clone();
+    let fgstate = state.clone();
+
+    let (stop_tx, stop_rx) = watch::channel(false);
 
     tokio::task::spawn(async move {
+        shutdown_signal().await;
+        info!("Shutdown requested, draining in-flight requests...");
+        let _ = stop_tx.send(true);
+    });
+
+    let mut bgstop = stop_rx.clone();
+    let tracker_task = tokio::task::spawn(async move {
         debug!("- Tracker thread spawned!");
+        let tracker = bgstate.read().await.tracker.clone();
         loop {
             let unow = utime(SystemTime::now());
-            let unext = bgstate.write().await.tracker.write().await.tick(unow).await;
-            bgstate.write().await.tracker.write().await.txn_tick().await;
-            tokio::time::sleep(Duration::from_secs((unext - unow).try_into().unwrap())).await
+            let unext = {
+                let mut tracker = tracker.write().await;
+                let unext = match tracker.tick(unow).await {
+                    Ok(unext) => unext,
+                    Err(e) => {
+                        warn!(
+                            "Could not write tracker journal, settlement postponed: {}",
+                            e
+                        );
+                        unow + SETTLEMENT_RETRY
+                    }
+                };
+                tracker.txn_tick().await;
+                unext
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs((unext - unow).try_into().unwrap())) => (),
+                _ = bgstop.changed() => break,
+            }
         }
+        debug!("- Tracker thread exited.");
     });
 
-    /*
-    tokio::task::spawn(async move {
-        debug!("- Withdrawal status update thread spawned!");
-        tokio::time::sleep(Duration::from_secs(5)).await;
-        debug!("- Sending withdrawal status update.");
-        txn_tx
-            .send(BalanceUpdate {
-                relay: "dummy".to_string(),
-                action: tracker::Action::Apply,
-            })
-            .await
-            .unwrap();
-        debug!("- Withdrawal status update thread exited.");
-    });
-    */
-
     let app = NormalizePathLayer::trim_trailing_slash().layer(
         Router::new()
             .route("/info", get(directory::info_get_handler))
@@ -108,6 +160,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     .post(directory::relays_post_handler)
                     .delete(directory::relays_delete_handler),
             )
+            .route("/relays/heartbeat", post(directory::heartbeat_post_handler))
             .route(
                 "/issue-accesskeys",
                 post(auth::issue_accesskeys_post_handler),
@@ -117,19 +170,78 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 post(contract::activate_post_handler),
             )
             .route("/submit", post(contract::submit_post_handler))
+            .route("/submit/batch", post(contract::submit_batch_post_handler))
             .route("/withdraw", post(contract::withdraw_post_handler))
             .route(
                 "/verify-withdrawal-request",
                 post(auth::verify_withdrawal_request_post_handler),
             )
             .route("/payout/balance", get(contract::balance_get_handler))
+            .route("/payout/receipts", get(contract::receipts_get_handler))
+            .route("/payout/statement", get(contract::statement_get_handler))
+            .route(
+                "/payout/withdrawal-state",
+                post(contract::withdrawal_state_post_handler),
+            )
             .with_state(state),
     );
 
-    axum::Server::bind(&cfg.address.parse().unwrap())
+    let mut srvstop = stop_rx.clone();
+    let server = axum::Server::bind(&cfg.address.parse().unwrap())
         .serve(app.into_make_service())
-        .await
-        .unwrap();
+        .with_graceful_shutdown(async move {
+            let _ = srvstop.changed().await;
+        });
+
+    let mut drainstop = stop_rx.clone();
+    tokio::select! {
+        r = server => r.unwrap(),
+        _ = async {
+            let _ = drainstop.changed().await;
+            tokio::time::sleep(drain_timeout).await
+        } => warn!("Requests still in flight after {:?}, shutting down anyway.", drain_timeout),
+    }
+
+    // the tracker thread may be mid-tick, let it finish before the final tick
+    tracker_task.await?;
+
+    info!("Flushing tracker state...");
+    let st = fgstate.read().await;
+    let mut tracker = st.tracker.write().await;
+    if let Err(e) = tracker.tick(utime(SystemTime::now())).await {
+        warn!(
+            "Could not write tracker journal, final settlement skipped: {}",
+            e
+        );
+    }
+    tracker.txn_tick().await;
+    tracker.flush().await?;
+    info!("Tracker state flushed, exiting.");
 
     Ok(())
 }
+
+// resolves on SIGINT or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install SIGINT handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => (),
+        _ = terminate => (),
+    }
+}
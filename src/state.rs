@@ -1,20 +1,25 @@
 use crate::{
-    api::{Public, Relay},
-    contract::tracker::{BalanceUpdate, Tracker},
+    api::Public,
+    contract::{
+        payment::Registry,
+        tracker::{BalanceUpdate, Tracker},
+        watcher::SafePending,
+    },
+    directory::RelayStore,
 };
 use axum::extract::State;
-use std::{collections::HashMap, sync::Arc};
+use std::sync::Arc;
 use tokio::sync::{mpsc::Sender, RwLock};
-use ws_common::api::Withdrawal;
 
 // handler shared state
 #[derive(Clone)]
 pub struct Custom {
-    pub relays: HashMap<String, Relay>,
+    pub directory: RelayStore,
     pub public: Public,
     pub tracker: Arc<RwLock<Tracker>>,
     pub txn_tx: Sender<BalanceUpdate>,
-    pub watcher_tx: Sender<Withdrawal>,
+    pub pending: SafePending,
+    pub payments: Arc<Registry>,
 }
 
 pub type SafeInner = Arc<RwLock<Custom>>;